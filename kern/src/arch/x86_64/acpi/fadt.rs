@@ -0,0 +1,87 @@
+use super::{find_table, read_table, SdtHeader};
+
+use x86_64::PhysAddr;
+
+pub const GAS_SYSTEM_MEMORY: u8 = 0;
+pub const GAS_SYSTEM_IO: u8 = 1;
+pub const GAS_PCI_CONFIG: u8 = 2;
+
+pub const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+pub const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    pub reserved0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_cnt: u8,
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    pub pm2_cnt_blk: u32,
+    pub pm_tmr_blk: u32,
+    pub gpe0_blk: u32,
+    pub gpe1_blk: u32,
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    pub pm2_cnt_len: u8,
+    pub pm_tmr_len: u8,
+    pub gpe0_blk_len: u8,
+    pub gpe1_blk_len: u8,
+    pub gpe1_base: u8,
+    pub cst_cnt: u8,
+    pub p_lvl2_lat: u16,
+    pub p_lvl3_lat: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alrm: u8,
+    pub mon_alrm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    pub reserved1: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+}
+
+impl Fadt {
+    pub fn dsdt_address(&self) -> PhysAddr {
+        if self.x_dsdt != 0 {
+            PhysAddr::new(self.x_dsdt)
+        } else {
+            PhysAddr::new(self.dsdt as u64)
+        }
+    }
+}
+
+pub fn parse() -> Option<Fadt> {
+    let addr = find_table(b"FACP")?;
+    Some(unsafe { read_table(addr) })
+}
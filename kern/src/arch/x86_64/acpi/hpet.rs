@@ -0,0 +1,37 @@
+use super::{fadt::GenericAddress, find_table, read_table, SdtHeader};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawHpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub address: u64,
+    pub number: u8,
+    pub min_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+}
+
+pub fn parse() -> Option<Hpet> {
+    let addr = find_table(b"HPET")?;
+    let raw: RawHpet = unsafe { read_table(addr) };
+    let id = raw.event_timer_block_id;
+
+    Some(Hpet {
+        address: raw.base_address.address,
+        number: raw.hpet_number,
+        min_tick: raw.min_tick,
+        comparators: ((id >> 8) & 0x1F) as u8 + 1,
+        counter_64bit: id & (1 << 13) != 0,
+        legacy_replacement: id & (1 << 15) != 0,
+    })
+}
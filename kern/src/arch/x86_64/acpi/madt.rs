@@ -0,0 +1,137 @@
+use super::{find_table, header, read_phys, SdtHeader};
+
+use alloc::vec::Vec;
+use core::mem;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+pub const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// Disabled now but may be hot-added later, never started at boot
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0x3 == 0x3
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0x3 == 0x3
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub lapic_address: u64,
+    pub flags: u32,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+pub fn parse() -> Option<Madt> {
+    let addr = find_table(b"APIC")?;
+    let len = header(addr).length as usize;
+    let body = addr + mem::size_of::<SdtHeader>();
+
+    let mut madt = Madt {
+        lapic_address: unsafe { read_phys::<u32>(body) } as u64,
+        flags: unsafe { read_phys::<u32>(body + 4usize) },
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    let mut offset = mem::size_of::<SdtHeader>() + 8;
+    while offset + 2 <= len {
+        let entry = addr + offset;
+        let kind: u8 = unsafe { read_phys(entry) };
+        let entry_len: u8 = unsafe { read_phys(entry + 1usize) };
+        if entry_len < 2 {
+            break;
+        }
+
+        unsafe {
+            match kind {
+                ENTRY_LOCAL_APIC => {
+                    let flags: u32 = read_phys(entry + 4usize);
+                    madt.processors.push(Processor {
+                        processor_id: read_phys::<u8>(entry + 2usize) as u32,
+                        apic_id: read_phys::<u8>(entry + 3usize) as u32,
+                        enabled: flags & LOCAL_APIC_ENABLED != 0,
+                        online_capable: flags & LOCAL_APIC_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_LOCAL_X2APIC => {
+                    let flags: u32 = read_phys(entry + 8usize);
+                    madt.processors.push(Processor {
+                        processor_id: read_phys(entry + 12usize),
+                        apic_id: read_phys(entry + 4usize),
+                        enabled: flags & LOCAL_APIC_ENABLED != 0,
+                        online_capable: flags & LOCAL_APIC_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                    id: read_phys(entry + 2usize),
+                    address: read_phys(entry + 4usize),
+                    gsi_base: read_phys(entry + 8usize),
+                }),
+                ENTRY_INTERRUPT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                    bus: read_phys(entry + 2usize),
+                    source: read_phys(entry + 3usize),
+                    gsi: read_phys(entry + 4usize),
+                    flags: read_phys(entry + 8usize),
+                }),
+                ENTRY_LOCAL_APIC_NMI => madt.nmis.push(LocalApicNmi {
+                    processor_id: read_phys(entry + 2usize),
+                    flags: read_phys(entry + 3usize),
+                    lint: read_phys(entry + 5usize),
+                }),
+                ENTRY_LOCAL_APIC_ADDRESS => madt.lapic_address = read_phys(entry + 4usize),
+                _ => {}
+            }
+        }
+
+        offset += entry_len as usize;
+    }
+
+    Some(madt)
+}
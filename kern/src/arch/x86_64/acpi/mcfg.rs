@@ -0,0 +1,48 @@
+use super::{find_table, header, read_phys, SdtHeader};
+
+use alloc::vec::Vec;
+use core::mem;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct RawEntry {
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub fn parse() -> Vec<McfgEntry> {
+    let addr = match find_table(b"MCFG") {
+        Some(addr) => addr,
+        None => return Vec::new(),
+    };
+
+    // The entries follow the header and 8 reserved bytes
+    let first = mem::size_of::<SdtHeader>() + 8;
+    let count = match (header(addr).length as usize).checked_sub(first) {
+        Some(len) => len / mem::size_of::<RawEntry>(),
+        None => return Vec::new(),
+    };
+
+    (0..count)
+        .map(|i| {
+            let raw: RawEntry = unsafe { read_phys(addr + first + i * mem::size_of::<RawEntry>()) };
+            McfgEntry {
+                base: raw.base,
+                segment: raw.segment,
+                start_bus: raw.start_bus,
+                end_bus: raw.end_bus,
+            }
+        })
+        .collect()
+}
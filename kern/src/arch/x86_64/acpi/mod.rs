@@ -0,0 +1,214 @@
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use super::mem::paging::phys_to_virt;
+use crate::schema::sys::SysSchema;
+
+use alloc::{format, string::String, vec::Vec};
+use core::{mem, ptr, slice};
+use spinning::Once;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+pub struct AcpiInfo {
+    pub madt: Option<madt::Madt>,
+    pub fadt: Option<fadt::Fadt>,
+    pub hpet: Option<hpet::Hpet>,
+    pub mcfg: Vec<mcfg::McfgEntry>,
+}
+
+static TABLES: Once<Vec<PhysAddr>> = Once::new();
+static INFO: Once<AcpiInfo> = Once::new();
+
+pub fn init() {
+    TABLES.call_once(|| match find_rsdp() {
+        Some(rsdp) => walk(rsdp),
+        None => Vec::new(),
+    });
+
+    print!("ACPI tables found ({})", tables().len());
+    if tables().is_empty() {
+        fail!();
+    } else {
+        ok!();
+    }
+
+    INFO.call_once(|| AcpiInfo {
+        madt: madt::parse(),
+        fadt: fadt::parse(),
+        hpet: hpet::parse(),
+        mcfg: mcfg::parse(),
+    });
+
+    if let Some(madt) = &info().madt {
+        info!(
+            "MADT: {} CPUs, {} IOAPICs, {} interrupt overrides",
+            madt.processors.iter().filter(|cpu| cpu.enabled).count(),
+            madt.io_apics.len(),
+            madt.overrides.len()
+        );
+    }
+    if let Some(hpet) = &info().hpet {
+        info!(
+            "HPET at {:#x} ({} comparators)",
+            hpet.address, hpet.comparators
+        );
+    }
+    for entry in info().mcfg.iter() {
+        info!("MCFG: segment {} at {:#x}", entry.segment, entry.base);
+    }
+}
+
+pub fn info() -> &'static AcpiInfo {
+    INFO.wait()
+}
+
+fn signature_name(signature: &[u8; 4]) -> String {
+    signature.iter().map(|b| *b as char).collect()
+}
+
+pub fn register_sys(sys: &mut SysSchema) {
+    let mut tables: Vec<(String, PhysAddr)> = tables()
+        .iter()
+        .map(|addr| (signature_name(&header(*addr).signature), *addr))
+        .collect();
+    if let Some(fadt) = &info().fadt {
+        tables.push((String::from("DSDT"), fadt.dsdt_address()));
+    }
+
+    let mut seen: Vec<String> = Vec::new();
+    for (name, addr) in tables {
+        // Tables like SSDT may appear more than once, number the duplicates
+        let count = seen.iter().filter(|n| **n == name).count();
+        let path = match count {
+            0 => format!("acpi/{}", name),
+            n => format!("acpi/{}{}", name, n),
+        };
+        seen.push(name);
+
+        sys.insert(&path, move || table_bytes(addr).to_vec());
+    }
+}
+
+pub fn table_bytes(addr: PhysAddr) -> &'static [u8] {
+    let len = header(addr).length as usize;
+    unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) }
+}
+
+pub fn tables() -> &'static [PhysAddr] {
+    TABLES.wait()
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    tables()
+        .iter()
+        .find(|addr| header(**addr).signature == *signature)
+        .copied()
+}
+
+/// Reads a structure out of physical memory, ACPI tables make no alignment promises.
+pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+/// Reads a whole table, zero-filling fields past its length for older revisions.
+pub unsafe fn read_table<T: Copy>(addr: PhysAddr) -> T {
+    let mut table: T = mem::zeroed();
+    let len = (header(addr).length as usize).min(mem::size_of::<T>());
+    ptr::copy_nonoverlapping(
+        phys_to_virt(addr).as_ptr::<u8>(),
+        &mut table as *mut T as *mut u8,
+        len,
+    );
+    table
+}
+
+pub fn header(addr: PhysAddr) -> SdtHeader {
+    unsafe { read_phys(addr) }
+}
+
+fn checksum(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = (unsafe { read_phys::<u16>(PhysAddr::new(0x40E)) } as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for &(start, end) in areas.iter() {
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            let signature: [u8; 8] = unsafe { read_phys(addr) };
+            if &signature == b"RSD PTR " && checksum(addr, 20) {
+                return Some(addr);
+            }
+        }
+    }
+
+    None
+}
+
+fn walk(rsdp_addr: PhysAddr) -> Vec<PhysAddr> {
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+    // The XSDT pointer is only covered by the extended checksum
+    let xsdt_valid = rsdp.revision >= 2
+        && rsdp.xsdt_address != 0
+        && rsdp.length as usize >= mem::size_of::<Rsdp>()
+        && checksum(rsdp_addr, rsdp.length as usize);
+    let (root, entry_size) = if xsdt_valid {
+        (PhysAddr::new(rsdp.xsdt_address), mem::size_of::<u64>())
+    } else {
+        (
+            PhysAddr::new(rsdp.rsdt_address as u64),
+            mem::size_of::<u32>(),
+        )
+    };
+
+    let root_len = header(root).length as usize;
+    let count = match root_len.checked_sub(mem::size_of::<SdtHeader>()) {
+        Some(len) => len / entry_size,
+        None => return Vec::new(),
+    };
+    let entries = root + mem::size_of::<SdtHeader>();
+
+    (0..count)
+        .map(|i| {
+            let entry = entries + i * entry_size;
+            match entry_size {
+                8 => PhysAddr::new(unsafe { read_phys::<u64>(entry) }),
+                _ => PhysAddr::new(unsafe { read_phys::<u32>(entry) } as u64),
+            }
+        })
+        .filter(|addr| checksum(*addr, header(*addr).length as usize))
+        .collect()
+}
@@ -0,0 +1,134 @@
+use super::mem::paging::active_l4_table;
+
+use core::{mem, slice, str};
+use x86_64::{
+    structures::paging::{MapperAllSizes, OffsetPageTable},
+    VirtAddr,
+};
+
+const KSYMS_SIZE: usize = 512 * 1024;
+const MAX_FRAMES: usize = 32;
+
+#[repr(C, align(8))]
+struct Ksyms([u8; KSYMS_SIZE]);
+
+/// Filled in after linking by `tools/ksyms.py`, see `.cargo/config`.
+#[no_mangle]
+#[link_section = ".ksyms"]
+static mut KSYMS: Ksyms = Ksyms([0; KSYMS_SIZE]);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SymbolEntry {
+    addr: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+global_asm!(
+    r#"
+.pushsection .text
+.global backtrace_frame_pointer
+backtrace_frame_pointer:
+    movq %rbp, %rax
+    retq
+.popsection
+"#
+);
+
+extern "C" {
+    /// Returns the frame pointer of the caller.
+    fn backtrace_frame_pointer() -> u64;
+}
+
+fn table() -> Option<(&'static [SymbolEntry], &'static [u8])> {
+    let blob = unsafe { &KSYMS.0[..] };
+    if &blob[0..4] != b"KSYM" {
+        return None;
+    }
+
+    let mut count = [0u8; 4];
+    count.copy_from_slice(&blob[4..8]);
+    let count = u32::from_le_bytes(count) as usize;
+    let entries_len = count * mem::size_of::<SymbolEntry>();
+    if 8 + entries_len > blob.len() {
+        return None;
+    }
+
+    let entries = unsafe { slice::from_raw_parts(blob[8..].as_ptr() as *const SymbolEntry, count) };
+    Some((entries, &blob[8 + entries_len..]))
+}
+
+/// Finds the function containing `addr`, returning its name and the offset into it.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let (entries, strings) = table()?;
+    let idx = match entries.binary_search_by_key(&addr, |entry| entry.addr) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+
+    let entry = entries[idx];
+    let start = entry.name_offset as usize;
+    let name = strings.get(start..start + entry.name_len as usize)?;
+    Some((str::from_utf8(name).ok()?, addr - entry.addr))
+}
+
+/// Whether the 16 bytes of a frame record at `addr` can be read, garbage addresses are not.
+fn is_mapped(mapper: &OffsetPageTable, addr: u64) -> bool {
+    let last = match addr.checked_add(15) {
+        Some(last) => last,
+        None => return false,
+    };
+    match (VirtAddr::try_new(addr), VirtAddr::try_new(last)) {
+        (Ok(start), Ok(end)) => {
+            mapper.translate_addr(start).is_some() && mapper.translate_addr(end).is_some()
+        }
+        _ => false,
+    }
+}
+
+fn print_frame(depth: usize, addr: u64) {
+    match resolve(addr) {
+        Some((name, offset)) => {
+            emergency_println!("  #{:<2} {:016x} {}+{:#x}", depth, addr, name, offset)
+        }
+        None => emergency_println!("  #{:<2} {:016x} <unknown>", depth, addr),
+    }
+}
+
+/// Prints `rip` followed by every return address found by following the `rbp` chain.
+pub fn print(rip: u64, rbp: u64) {
+    let offset = *crate::PHYS_MEM_OFFSET.wait();
+    let mapper = unsafe { OffsetPageTable::new(active_l4_table(offset), offset) };
+
+    emergency_println!("Backtrace:");
+    print_frame(0, rip);
+
+    let mut rbp = rbp;
+    for depth in 1..MAX_FRAMES {
+        if rbp == 0 || rbp & 0x7 != 0 || !is_mapped(&mapper, rbp) {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        print_frame(depth, ret);
+
+        // Frames only ever move towards the stack base, anything else is garbage
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Prints the backtrace of the calling function.
+#[inline(never)]
+pub fn print_current() {
+    let rbp = unsafe { backtrace_frame_pointer() };
+    let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+    print(ret, next);
+}
@@ -0,0 +1,76 @@
+use super::{hpet, pit, tsc};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    Pit,
+    Hpet,
+    Tsc,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(Source::Pit as u8);
+// Kept in TSC ticks, the early steps run before it is calibrated
+static BOOT_STEPS: Mutex<Vec<(&'static str, u64)>> = Mutex::new(Vec::new());
+
+/// Picks the best clock source: an invariant TSC, then a 64-bit HPET, then PIT ticks.
+pub fn init() {
+    let hpet = hpet::init().is_ok();
+    let khz = tsc::calibrate();
+    let invariant = tsc::invariant();
+
+    let source = if invariant {
+        Source::Tsc
+    } else if hpet && hpet::counter_64bit() {
+        Source::Hpet
+    } else {
+        Source::Pit
+    };
+    SOURCE.store(source as u8, Ordering::SeqCst);
+
+    print!(
+        "Clock source {:?}, TSC at {}.{:03} MHz{}",
+        source,
+        khz / 1000,
+        khz % 1000,
+        if invariant { " (invariant)" } else { "" }
+    );
+    ok!();
+}
+
+pub fn source() -> Source {
+    match SOURCE.load(Ordering::Relaxed) {
+        2 => Source::Tsc,
+        1 => Source::Hpet,
+        _ => Source::Pit,
+    }
+}
+
+/// Nanoseconds on the selected source, only meaningful relative to another reading.
+pub fn nanos() -> u64 {
+    match source() {
+        Source::Tsc => tsc::to_nanos(tsc::read()),
+        Source::Hpet => hpet::nanos(),
+        Source::Pit => pit::ticks() * (1_000_000_000 / pit::TICK_HZ),
+    }
+}
+
+/// Runs one step of bringing the machine up and remembers how long it took.
+pub fn step(name: &'static str, f: impl FnOnce()) {
+    let start = tsc::read();
+    f();
+    let ticks = tsc::read() - start;
+    BOOT_STEPS.lock().push((name, ticks));
+}
+
+/// Every step run through `step` so far, with its duration in nanoseconds.
+pub fn boot_steps() -> Vec<(&'static str, u64)> {
+    BOOT_STEPS
+        .lock()
+        .iter()
+        .map(|(name, ticks)| (*name, tsc::to_nanos(*ticks)))
+        .collect()
+}
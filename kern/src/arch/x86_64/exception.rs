@@ -0,0 +1,544 @@
+use super::{
+    backtrace, gdb,
+    mem::{
+        fault::{self, Resolution},
+        paging::active_l4_table,
+    },
+    smp,
+};
+
+use core::{fmt, mem, sync::atomic::Ordering};
+use spin::RwLock;
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::{
+        idt::InterruptDescriptorTable,
+        paging::{MapperAllSizes, OffsetPageTable},
+    },
+    VirtAddr,
+};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const MACHINE_CHECK: u8 = 18;
+
+const NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// Register state pushed by the exception stubs, lowest address first.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionAction {
+    Resume,
+    KillTask,
+    Panic,
+}
+
+pub type ExceptionPolicy = fn(&ExceptionFrame) -> ExceptionAction;
+
+/// Callee-saved state captured by `guard`, restored when a faulting task is killed.
+#[derive(Default)]
+#[repr(C)]
+struct GuardContext {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+static POLICY: RwLock<ExceptionPolicy> = RwLock::new(default_policy);
+
+global_asm!(
+    r#"
+.macro exception_stub vector, has_error
+exception_stub_\vector:
+.if \has_error == 0
+    pushq $0
+.endif
+    pushq $\vector
+    jmp exception_common
+.endm
+
+.pushsection .text
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 15, 0
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 22, 0
+exception_stub 23, 0
+exception_stub 24, 0
+exception_stub 25, 0
+exception_stub 26, 0
+exception_stub 27, 0
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+exception_stub 31, 0
+
+exception_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, %rdi
+    cld
+    cmpq $7, 120(%rsp)
+    je device_not_available
+    # Compiled code uses SSE freely, the interrupted vector state is kept out of its way
+    movq %cr0, %rax
+    pushq %rax
+    clts
+    subq $520, %rsp
+    fxsave64 (%rsp)
+    callq exception_dispatch
+    fxrstor64 (%rsp)
+    addq $520, %rsp
+    popq %rax
+    movq %rax, %cr0
+exception_return:
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    addq $16, %rsp
+    iretq
+
+# Lazy FPU switch, the legacy state is saved before any compiled code can touch it
+device_not_available:
+    clts
+    subq $512, %rsp
+    fxsave64 (%rsp)
+    movq %rsp, %rdi
+    callq fpu_switch
+    addq $512, %rsp
+    movq %rax, %rdi
+    cmpb $0, FPU_XSAVE(%rip)
+    je 1f
+    movl $-1, %eax
+    movl $-1, %edx
+    xrstor64 (%rdi)
+    jmp exception_return
+1:
+    fxrstor64 (%rdi)
+    jmp exception_return
+
+.global fault_guard_call
+fault_guard_call:
+    movq %rbx, 0(%rdi)
+    movq %rbp, 8(%rdi)
+    movq %r12, 16(%rdi)
+    movq %r13, 24(%rdi)
+    movq %r14, 32(%rdi)
+    movq %r15, 40(%rdi)
+    leaq 8(%rsp), %rax
+    movq %rax, 48(%rdi)
+    movq (%rsp), %rax
+    movq %rax, 56(%rdi)
+    movq %rdx, %rdi
+    subq $8, %rsp
+    callq *%rsi
+    addq $8, %rsp
+    xorl %eax, %eax
+    retq
+
+.global fault_guard_abort
+fault_guard_abort:
+    movq 0(%rdi), %rbx
+    movq 8(%rdi), %rbp
+    movq 16(%rdi), %r12
+    movq 24(%rdi), %r13
+    movq 32(%rdi), %r14
+    movq 40(%rdi), %r15
+    movq 48(%rdi), %rsp
+    movl $1, %eax
+    jmpq *56(%rdi)
+.popsection
+
+.pushsection .rodata
+.align 8
+.global exception_stub_table
+exception_stub_table:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_stub_\vector
+.endr
+.popsection
+"#
+);
+
+extern "C" {
+    static exception_stub_table: [u64; 32];
+    fn fault_guard_call(ctx: *mut GuardContext, f: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+    fn fault_guard_abort();
+}
+
+fn stub(vector: u8) -> usize {
+    unsafe { exception_stub_table[vector as usize] as usize }
+}
+
+macro_rules! install_stubs {
+    ($idt:ident; $($field:ident => $vector:expr),* $(,)?) => {
+        $(
+            $idt.$field.set_handler_fn(mem::transmute(stub($vector)));
+        )*
+    };
+}
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        install_stubs! { idt;
+            divide_error => 0,
+            debug => 1,
+            non_maskable_interrupt => 2,
+            breakpoint => 3,
+            overflow => 4,
+            bound_range_exceeded => 5,
+            invalid_opcode => 6,
+            device_not_available => 7,
+            invalid_tss => 10,
+            segment_not_present => 11,
+            stack_segment_fault => 12,
+            general_protection_fault => 13,
+            x87_floating_point => 16,
+            alignment_check => 17,
+            machine_check => 18,
+            simd_floating_point => 19,
+            virtualization => 20,
+            security_exception => 30,
+        }
+
+        idt.double_fault
+            .set_handler_fn(mem::transmute(stub(DOUBLE_FAULT)))
+            .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.page_fault
+            .set_handler_fn(mem::transmute(stub(PAGE_FAULT)))
+            .set_stack_index(super::gdt::PAGE_FAULT_IST_INDEX);
+    }
+}
+
+/// Replaces the hook that decides what happens to recoverable exceptions.
+pub fn set_policy(policy: ExceptionPolicy) {
+    *POLICY.write() = policy;
+}
+
+/// Whether a lock that a killed task would leave held forever is taken. The owner isn't known,
+/// so a lock held by another CPU counts too and the fault panics instead of risking a deadlock.
+fn kernel_lock_held() -> bool {
+    super::WRITER.try_lock().is_none()
+        || crate::KERNEL_SPACE.wait().try_lock().is_none()
+        || crate::FRAME_ALLOC.wait().try_lock().is_none()
+        || super::mem::alloc::is_locked()
+}
+
+fn default_policy(frame: &ExceptionFrame) -> ExceptionAction {
+    match frame.vector as u8 {
+        DEBUG | NMI | BREAKPOINT | OVERFLOW => ExceptionAction::Resume,
+        _ if kernel_lock_held() => ExceptionAction::Panic,
+        _ => ExceptionAction::KillTask,
+    }
+}
+
+/// Runs `f`, returning `None` if an exception policy killed it part way through.
+pub fn guard<R>(f: impl FnOnce() -> R) -> Option<R> {
+    extern "C" fn call<F: FnMut()>(data: *mut u8) {
+        unsafe { (*(data as *mut F))() }
+    }
+
+    fn entry_of<F: FnMut()>(_: &F) -> extern "C" fn(*mut u8) {
+        call::<F>
+    }
+
+    let slot = match smp::cpus().get(smp::cpu_id()) {
+        Some(cpu) => &cpu.fault_guard,
+        None => return Some(f()),
+    };
+
+    let mut f = Some(f);
+    let mut result = None;
+    let mut run = || result = f.take().map(|f| f());
+    let mut ctx = GuardContext::default();
+
+    let prev = slot.swap(&mut ctx as *mut GuardContext as u64, Ordering::SeqCst);
+    let aborted =
+        unsafe { fault_guard_call(&mut ctx, entry_of(&run), &mut run as *mut _ as *mut u8) };
+    slot.store(prev, Ordering::SeqCst);
+
+    match aborted {
+        0 => result,
+        _ => None,
+    }
+}
+
+fn current_guard() -> Option<u64> {
+    let cpu = smp::cpus().get(smp::cpu_id())?;
+    match cpu.fault_guard.load(Ordering::SeqCst) {
+        0 => None,
+        ctx => Some(ctx),
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    if vector == NMI && smp::stop_requested() {
+        smp::park();
+    }
+    if (vector == BREAKPOINT || vector == DEBUG) && gdb::connected() {
+        gdb::handle(frame);
+        return;
+    }
+
+    if vector == PAGE_FAULT {
+        match fault::resolve(Cr2::read(), frame.error_code) {
+            Resolution::Resolved => return,
+            Resolution::StackOverflow(name) => emergency_println!("Stack overflow in {}", name),
+            Resolution::OutOfMemory(name) => emergency_println!("Out of memory backing {}", name),
+            Resolution::PagerFailed(name, err) => {
+                emergency_println!("Failed to page in {}: {}", name, err)
+            }
+            Resolution::Unhandled => {}
+        }
+    }
+
+    emergency_println!("EXCEPTION: {} (vector {})", NAMES[vector as usize], vector);
+    if vector != BREAKPOINT {
+        emergency_println!("{}", ErrorCode(vector, frame.error_code));
+        emergency_println!("{}", frame);
+        dump_instruction(frame.rip);
+        backtrace::print(frame.rip, frame.rbp);
+    }
+
+    let action = match vector {
+        DOUBLE_FAULT | MACHINE_CHECK => ExceptionAction::Panic,
+        _ => (*POLICY.read())(frame),
+    };
+
+    match action {
+        ExceptionAction::Resume => {}
+        ExceptionAction::KillTask => match current_guard() {
+            Some(ctx) => {
+                emergency_println!("Killing faulting task");
+                frame.rip = fault_guard_abort as u64;
+                frame.rdi = ctx;
+            }
+            None => panic!("{} outside of a task", NAMES[vector as usize]),
+        },
+        ExceptionAction::Panic => panic!("Unrecoverable {}", NAMES[vector as usize]),
+    }
+}
+
+struct ErrorCode(u8, u64);
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ErrorCode(vector, code) = *self;
+        match vector {
+            PAGE_FAULT => write!(
+                f,
+                "Error Code: {:#x} ({}, {}, {}{}{}) at {:?}",
+                code,
+                if code & 1 != 0 {
+                    "protection violation"
+                } else {
+                    "not present"
+                },
+                if code & 2 != 0 { "write" } else { "read" },
+                if code & 4 != 0 { "user" } else { "kernel" },
+                if code & 8 != 0 {
+                    ", reserved bit set"
+                } else {
+                    ""
+                },
+                if code & 16 != 0 {
+                    ", instruction fetch"
+                } else {
+                    ""
+                },
+                Cr2::read()
+            ),
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION
+                if code != 0 =>
+            {
+                let table = match (code >> 1) & 0x3 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "Error Code: {:#x} ({} index {}{})",
+                    code,
+                    table,
+                    (code >> 3) & 0x1FFF,
+                    if code & 1 != 0 { ", external" } else { "" }
+                )
+            }
+            _ => write!(f, "Error Code: {:#x}", code),
+        }
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016x} RFL={:016x} CS={:04x} SS={:04x}",
+            self.rip, self.rflags, self.cs, self.ss
+        )?;
+        write!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+fn dump_instruction(rip: u64) {
+    let offset = *crate::PHYS_MEM_OFFSET.wait();
+    let mapper = unsafe { OffsetPageTable::new(active_l4_table(offset), offset) };
+    let start = VirtAddr::new(rip);
+    let end = start + 15u64;
+    if mapper.translate_addr(start).is_none() || mapper.translate_addr(end).is_none() {
+        emergency_println!("Code: <unmapped>");
+        return;
+    }
+
+    emergency_print!("Code:");
+    for i in 0..16 {
+        emergency_print!(" {:02x}", unsafe { *((rip + i) as *const u8) });
+    }
+    emergency_println!();
+}
@@ -0,0 +1,268 @@
+use super::smp;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, _fxrstor64, _fxsave64, _xrstor64, _xsave64, _xsetbv},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+use spinning::Once;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+};
+
+const LEGACY_SIZE: usize = 512;
+const XSAVE_HEADER_SIZE: usize = 64;
+const XSTATE_BV: usize = LEGACY_SIZE;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+// Read by the #NM stub in exception.rs to pick between xrstor and fxrstor
+#[no_mangle]
+static FPU_XSAVE: AtomicBool = AtomicBool::new(false);
+static AVX: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(LEGACY_SIZE);
+
+static INIT_STATE: Once<FpuState> = Once::new();
+static BOOT_CPU: CpuFpu = CpuFpu::new();
+
+/// Saved x87/SSE/AVX registers of one task, loaded the first time it touches them after
+/// being switched to. CPUs refer to it by its area, so the state itself may move.
+pub struct FpuState {
+    area: NonNull<u8>,
+    size: usize,
+}
+
+// The area is only touched by the CPU running its owner
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    /// A state with every register cleared and the default control words.
+    pub fn new() -> Self {
+        let size = AREA_SIZE.load(Ordering::SeqCst);
+        let layout = Layout::from_size_align(size, 64).unwrap();
+        let area =
+            NonNull::new(unsafe { alloc_zeroed(layout) }).expect("Out of memory for FPU state");
+
+        unsafe {
+            (area.as_ptr() as *mut u16).write(FCW_DEFAULT);
+            (area.as_ptr().add(24) as *mut u32).write(MXCSR_DEFAULT);
+        }
+        Self { area, size }
+    }
+
+    #[target_feature(enable = "xsave,fxsr")]
+    unsafe fn save(&self) {
+        match FPU_XSAVE.load(Ordering::Relaxed) {
+            true => _xsave64(self.area.as_ptr(), !0),
+            false => _fxsave64(self.area.as_ptr()),
+        }
+    }
+
+    #[target_feature(enable = "xsave,fxsr")]
+    unsafe fn restore(&self) {
+        match FPU_XSAVE.load(Ordering::Relaxed) {
+            true => _xrstor64(self.area.as_ptr(), !0),
+            false => _fxrstor64(self.area.as_ptr()),
+        }
+    }
+}
+
+/// Saves the live registers to `area`, with the legacy region taken from `legacy` instead. The
+/// #NM stub captures it before any compiled code gets to run, while AVX state is only ever
+/// changed by code that asked for it.
+#[target_feature(enable = "xsave")]
+unsafe fn save_from(area: *mut u8, legacy: *const u8) {
+    if FPU_XSAVE.load(Ordering::Relaxed) {
+        _xsave64(area, !0);
+        let bv = area.add(XSTATE_BV) as *mut u64;
+        bv.write(bv.read() | XCR0_X87 | XCR0_SSE);
+    }
+    ptr::copy_nonoverlapping(legacy, area, LEGACY_SIZE);
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // Nothing may be left pointing at the area, the registers it owned are simply dropped
+        let this = self.area.as_ptr();
+        let cpus = smp::cpus().iter().map(|cpu| &cpu.fpu);
+        for cpu in cpus.chain(Some(&BOOT_CPU)) {
+            cpu.owner
+                .compare_and_swap(this, ptr::null_mut(), Ordering::SeqCst);
+            cpu.current
+                .compare_and_swap(this, ptr::null_mut(), Ordering::SeqCst);
+        }
+
+        let layout = Layout::from_size_align(self.size, 64).unwrap();
+        unsafe { dealloc(self.area.as_ptr(), layout) };
+    }
+}
+
+/// Which state the registers of a CPU hold and which one its running code expects.
+/// `CR0.TS` is set whenever the two differ.
+pub struct CpuFpu {
+    owner: AtomicPtr<u8>,
+    current: AtomicPtr<u8>,
+    // Where `simd` keeps the registers it displaced, allocated up front since it may run in an
+    // interrupt handler
+    simd_saved: Once<FpuState>,
+    in_simd: AtomicBool,
+}
+
+impl CpuFpu {
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicPtr::new(ptr::null_mut()),
+            current: AtomicPtr::new(ptr::null_mut()),
+            simd_saved: Once::new(),
+            in_simd: AtomicBool::new(false),
+        }
+    }
+
+    /// Like `new`, for CPUs brought up once the size of saved states is known.
+    pub fn with_simd_area() -> Self {
+        let cpu = Self::new();
+        cpu.simd_saved.call_once(FpuState::new);
+        cpu
+    }
+}
+
+fn this_cpu() -> &'static CpuFpu {
+    match smp::cpus().get(smp::cpu_id()) {
+        Some(cpu) => &cpu.fpu,
+        None => &BOOT_CPU,
+    }
+}
+
+fn enable() -> (bool, bool) {
+    let features = unsafe { __cpuid(1) };
+    let xsave = features.ecx & CPUID_XSAVE != 0;
+    let avx = xsave && features.ecx & CPUID_AVX != 0;
+
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| {
+            cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if xsave {
+                cr4.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+        if xsave {
+            enable_xcr0(XCR0_X87 | XCR0_SSE | if avx { XCR0_AVX } else { 0 });
+        }
+    }
+
+    (xsave, avx)
+}
+
+/// Enables SSE, and XSAVE with AVX where the CPU has them. The boot CPU decides the layout of
+/// saved states, every other CPU is assumed to match it.
+pub fn init() {
+    let (xsave, avx) = enable();
+    let size = match xsave {
+        true => unsafe { __cpuid_count(0xD, 0) }.ebx as usize,
+        false => LEGACY_SIZE,
+    };
+    FPU_XSAVE.store(xsave, Ordering::SeqCst);
+    AVX.store(avx, Ordering::SeqCst);
+    AREA_SIZE.store(size.max(LEGACY_SIZE + XSAVE_HEADER_SIZE), Ordering::SeqCst);
+    INIT_STATE.call_once(FpuState::new);
+    BOOT_CPU.simd_saved.call_once(FpuState::new);
+
+    print!(
+        "FPU enabled (SSE{}{}, {} byte state)",
+        if xsave { ", XSAVE" } else { "" },
+        if avx { ", AVX" } else { "" },
+        AREA_SIZE.load(Ordering::SeqCst)
+    );
+    ok!();
+}
+
+pub fn init_ap() {
+    enable();
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn enable_xcr0(mask: u64) {
+    _xsetbv(0, mask);
+}
+
+pub fn has_avx() -> bool {
+    AVX.load(Ordering::Relaxed)
+}
+
+/// Makes `state` the one the calling CPU's code expects from now on, `None` being the kernel's
+/// own scratch state. The registers are only swapped if the new owner touches them.
+pub fn switch_to(state: Option<&FpuState>) {
+    let state = state.map_or(ptr::null_mut(), |state| state.area.as_ptr());
+    interrupts::without_interrupts(|| {
+        let cpu = this_cpu();
+        cpu.current.store(state, Ordering::SeqCst);
+        unsafe {
+            Cr0::update(|cr0| {
+                cr0.set(
+                    Cr0Flags::TASK_SWITCHED,
+                    cpu.owner.load(Ordering::SeqCst) != state,
+                )
+            })
+        };
+    });
+}
+
+/// Called by the #NM stub with the legacy state it saved. Stores the registers away for their
+/// owner and returns the area to load for the code that trapped.
+#[no_mangle]
+extern "C" fn fpu_switch(legacy: *const u8) -> *const u8 {
+    let cpu = this_cpu();
+    let owner = cpu.owner.load(Ordering::SeqCst);
+    if !owner.is_null() {
+        unsafe { save_from(owner, legacy) };
+    }
+
+    let current = cpu.current.load(Ordering::SeqCst);
+    cpu.owner.store(current, Ordering::SeqCst);
+    match current.is_null() {
+        true => INIT_STATE.wait().area.as_ptr(),
+        false => current,
+    }
+}
+
+/// Runs `f` with the whole register file to itself, for code using AVX behind
+/// `#[target_feature]`. Whatever was live is put back afterwards, which makes this safe in
+/// interrupt handlers, where only the SSE registers are preserved otherwise.
+pub fn simd<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let cpu = this_cpu();
+        // A nested call already runs with the registers to itself, the outer one restores them
+        if cpu.in_simd.swap(true, Ordering::SeqCst) {
+            return f();
+        }
+
+        let saved = cpu.simd_saved.wait();
+        let cr0 = Cr0::read();
+        unsafe {
+            Cr0::write(cr0 - Cr0Flags::TASK_SWITCHED);
+            saved.save();
+        }
+        let result = f();
+        unsafe {
+            saved.restore();
+            Cr0::write(cr0);
+        }
+        cpu.in_simd.store(false, Ordering::SeqCst);
+        result
+    })
+}
@@ -0,0 +1,376 @@
+use super::{exception::ExceptionFrame, irq, mem::paging::active_l4_table};
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::port::Port,
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{MapperAllSizes, OffsetPageTable},
+    VirtAddr,
+};
+
+const COM2: u16 = 0x2F8;
+const COM2_IRQ: u8 = 3;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const SIGTRAP: u8 = 5;
+
+// rax..r15 and rip are 64 bits wide, eflags and the segment registers 32
+const GDB_REGS64: usize = 17;
+const GDB_REGS32: usize = 7;
+
+// Two hex digits a byte have to fit the PacketSize given in qSupported
+const MAX_READ: u64 = 0x1000 / 2;
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static ATTACHING: AtomicBool = AtomicBool::new(false);
+
+fn port(offset: u16) -> Port<u8> {
+    Port::new(COM2 + offset)
+}
+
+pub fn init() {
+    unsafe {
+        port(1).write(0x00);
+        port(3).write(0x80);
+        port(0).write(0x01);
+        port(1).write(0x00);
+        port(3).write(0x03);
+        port(2).write(0xC7);
+        port(4).write(0x0B);
+        // Interrupt on received data so gdb can break into a running kernel
+        port(1).write(0x01);
+    }
+
+    check_ok!(
+        "GDB stub listening on COM2",
+        irq::register(COM2_IRQ, "gdb", serial_interrupt)
+    );
+}
+
+fn serial_interrupt() {
+    if unsafe { port(5).read() } & LSR_DATA_READY != 0 {
+        // Later input, such as a Ctrl-C break, still expects a stop reply
+        if !CONNECTED.swap(true, Ordering::SeqCst) {
+            ATTACHING.store(true, Ordering::SeqCst);
+        }
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Whether a debugger has talked to the stub, breakpoints are only handed over after that.
+pub fn connected() -> bool {
+    CONNECTED.load(Ordering::SeqCst)
+}
+
+fn read_byte() -> u8 {
+    unsafe {
+        while port(5).read() & LSR_DATA_READY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        port(0).read()
+    }
+}
+
+fn write_byte(b: u8) {
+    unsafe {
+        while port(5).read() & LSR_THR_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        port(0).write(b);
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |acc, c| Some(acc << 4 | hex_digit(*c)? as u64))
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    s.chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some(hex_digit(*hi)? << 4 | hex_digit(*lo)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_packet() -> Vec<u8> {
+    loop {
+        while read_byte() != b'$' {}
+
+        let mut packet = Vec::new();
+        let mut sum = 0u8;
+        loop {
+            match read_byte() {
+                b'#' => break,
+                b => {
+                    sum = sum.wrapping_add(b);
+                    packet.push(b);
+                }
+            }
+        }
+
+        let checksum = parse_hex(&[read_byte(), read_byte()]);
+        if checksum == Some(sum as u64) {
+            write_byte(b'+');
+            return packet;
+        }
+        write_byte(b'-');
+    }
+}
+
+fn send_packet(data: &str) {
+    loop {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write_byte(b'$');
+        data.bytes().for_each(write_byte);
+        write_byte(b'#');
+        let mut tail = String::new();
+        write!(tail, "{:02x}", sum).ok();
+        tail.bytes().for_each(write_byte);
+
+        match read_byte() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+fn push_le(out: &mut String, val: u64, bytes: usize) {
+    for b in val.to_le_bytes().iter().take(bytes) {
+        write!(out, "{:02x}", b).ok();
+    }
+}
+
+fn register(frame: &mut ExceptionFrame, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    })
+}
+
+fn register_width(n: usize) -> usize {
+    if n < GDB_REGS64 {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_registers(frame: &mut ExceptionFrame) -> String {
+    let mut out = String::new();
+    for n in 0..GDB_REGS64 + GDB_REGS32 {
+        let val = register(frame, n).map_or(0, |reg| *reg);
+        push_le(&mut out, val, register_width(n));
+    }
+    out
+}
+
+fn write_registers(frame: &mut ExceptionFrame, data: &[u8]) {
+    let mut offset = 0;
+    for n in 0..GDB_REGS64 + GDB_REGS32 {
+        let width = register_width(n);
+        let bytes = match data.get(offset..offset + width) {
+            Some(bytes) => bytes,
+            None => break,
+        };
+        offset += width;
+
+        let mut raw = [0u8; 8];
+        raw[..width].copy_from_slice(bytes);
+        if let Some(reg) = register(frame, n) {
+            *reg = u64::from_le_bytes(raw);
+        }
+    }
+}
+
+fn mapped(addr: u64, len: u64) -> bool {
+    let offset = *crate::PHYS_MEM_OFFSET.wait();
+    let mapper = unsafe { OffsetPageTable::new(active_l4_table(offset), offset) };
+    let last = match addr.checked_add(len.max(1) - 1) {
+        Some(last) => last,
+        None => return false,
+    };
+    (addr & !0xFFF..=last)
+        .step_by(0x1000)
+        .all(|page| match VirtAddr::try_new(page) {
+            Ok(page) => mapper.translate_addr(page).is_some(),
+            Err(_) => false,
+        })
+}
+
+fn read_memory(addr: u64, len: u64) -> Option<String> {
+    // A short read is a valid reply, gdb asks again for the rest
+    let len = len.min(MAX_READ);
+    if !mapped(addr, len) {
+        return None;
+    }
+    let mut out = String::new();
+    for i in 0..len {
+        write!(out, "{:02x}", unsafe { *((addr + i) as *const u8) }).ok();
+    }
+    Some(out)
+}
+
+fn write_memory(addr: u64, data: &[u8]) -> Option<()> {
+    if !mapped(addr, data.len() as u64) {
+        return None;
+    }
+
+    // Breakpoints land in read-only kernel text, lift write protection while patching
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        for (i, b) in data.iter().enumerate() {
+            *((addr + i as u64) as *mut u8) = *b;
+        }
+        Cr0::write(cr0);
+    }
+    Some(())
+}
+
+fn split_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|c| *c == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+fn resume_at(frame: &mut ExceptionFrame, args: &[u8]) {
+    if let Some(addr) = parse_hex(args) {
+        frame.rip = addr;
+    }
+}
+
+/// Runs the remote protocol until the debugger resumes execution.
+pub fn handle(frame: &mut ExceptionFrame) {
+    frame.rflags &= !RFLAGS_TF;
+
+    let mut stop = String::new();
+    write!(stop, "S{:02x}", SIGTRAP).ok();
+    // A freshly attached debugger asks for the stop reason itself
+    if !ATTACHING.swap(false, Ordering::SeqCst) {
+        send_packet(&stop);
+    }
+
+    loop {
+        let packet = read_packet();
+        let (cmd, args) = match packet.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => continue,
+        };
+
+        match cmd {
+            b'?' => send_packet(&stop),
+            b'g' => send_packet(&read_registers(frame)),
+            b'G' => match decode_hex(args) {
+                Some(data) => {
+                    write_registers(frame, &data);
+                    send_packet("OK");
+                }
+                None => send_packet("E01"),
+            },
+            b'p' => match parse_hex(args).and_then(|n| {
+                let width = register_width(n as usize);
+                register(frame, n as usize).map(|reg| (*reg, width))
+            }) {
+                Some((val, width)) => {
+                    let mut out = String::new();
+                    push_le(&mut out, val, width);
+                    send_packet(&out);
+                }
+                None => send_packet("E01"),
+            },
+            b'P' => {
+                let eq = args.iter().position(|c| *c == b'=');
+                let parsed = eq.and_then(|eq| {
+                    Some((
+                        parse_hex(&args[..eq])? as usize,
+                        decode_hex(&args[eq + 1..])?,
+                    ))
+                });
+                match parsed {
+                    Some((n, data)) if data.len() <= 8 => {
+                        let mut raw = [0u8; 8];
+                        raw[..data.len()].copy_from_slice(&data);
+                        if let Some(reg) = register(frame, n) {
+                            *reg = u64::from_le_bytes(raw);
+                        }
+                        send_packet("OK");
+                    }
+                    _ => send_packet("E01"),
+                }
+            }
+            b'm' => match split_addr_len(args).and_then(|(addr, len)| read_memory(addr, len)) {
+                Some(data) => send_packet(&data),
+                None => send_packet("E14"),
+            },
+            b'M' => {
+                let colon = args.iter().position(|c| *c == b':');
+                let written = colon.and_then(|colon| {
+                    let (addr, _) = split_addr_len(&args[..colon])?;
+                    write_memory(addr, &decode_hex(&args[colon + 1..])?)
+                });
+                match written {
+                    Some(()) => send_packet("OK"),
+                    None => send_packet("E14"),
+                }
+            }
+            b'c' => {
+                resume_at(frame, args);
+                return;
+            }
+            b's' => {
+                resume_at(frame, args);
+                frame.rflags |= RFLAGS_TF;
+                return;
+            }
+            b'D' | b'k' => {
+                send_packet("OK");
+                CONNECTED.store(false, Ordering::SeqCst);
+                return;
+            }
+            b'H' => send_packet("OK"),
+            b'q' if args.starts_with(b"Supported") => send_packet("PacketSize=1000"),
+            b'q' if args.starts_with(b"Attached") => send_packet("1"),
+            b'q' if args == b"C" => send_packet("QC1"),
+            _ => send_packet(""),
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use lazy_static::lazy_static;
+use super::smp;
+
+use alloc::{boxed::Box, vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use x86_64::{
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
@@ -8,36 +14,54 @@ use x86_64::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kern_code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let user_code = gdt.add_entry(Descriptor::user_code_segment());
-        let user_data = gdt.add_entry(Descriptor::user_data_segment());
-        let tss_selec = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                kern_code,
-                user_code,
-                user_data,
-                tss_selec,
-            },
-        )
+// Double faults still print a register dump and a backtrace before panicking
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+// Faults on lazily backed stacks are resolved here, the faulting stack has no room left.
+// The lower half is for a fault nested in the resolver, see `nested_ist`.
+const PAGE_FAULT_STACK_SIZE: usize = 32 * 1024;
+pub const PAGE_FAULT_NEST_DEPTH: u64 = 16 * 1024;
+
+/// A CPU's TSS along with the bottoms of its interrupt stacks, which the TSS doesn't keep.
+pub struct CpuTss {
+    tss: TaskStateSegment,
+    ist_bottom: [VirtAddr; 7],
+}
+
+static BSP_TSS: AtomicPtr<CpuTss> = AtomicPtr::new(ptr::null_mut());
+
+fn new_tss() -> &'static mut CpuTss {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let pf_stack = Box::leak(vec![0u8; PAGE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut cpu = CpuTss {
+        tss: TaskStateSegment::new(),
+        ist_bottom: [VirtAddr::zero(); 7],
     };
+    cpu.ist_bottom[DOUBLE_FAULT_IST_INDEX as usize] = VirtAddr::from_ptr(stack.as_ptr());
+    cpu.ist_bottom[PAGE_FAULT_IST_INDEX as usize] = VirtAddr::from_ptr(pf_stack.as_ptr());
+    cpu.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    cpu.tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(pf_stack.as_ptr()) + PAGE_FAULT_STACK_SIZE;
+    Box::leak(Box::new(cpu))
+}
+
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kern_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let tss_selec = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kern_code,
+            user_code,
+            user_data,
+            tss_selec,
+        },
+    )
 }
 
 #[allow(unused)]
@@ -48,14 +72,66 @@ struct Selectors {
     tss_selec: SegmentSelector,
 }
 
-pub fn init() {
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::{segmentation::set_cs, tables::load_tss};
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.kern_code);
-        load_tss(GDT.1.tss_selec);
+        set_cs(gdt.1.kern_code);
+        load_tss(gdt.1.tss_selec);
     }
+}
+
+pub fn init() {
+    let tss = new_tss();
+    BSP_TSS.store(tss, Ordering::SeqCst);
+    load(Box::leak(Box::new(build(&tss.tss))));
     print!("GDT loaded");
     ok!();
 }
+
+/// Gives an application processor its own TSS and GDT, the BSP's are already in use.
+pub fn init_ap() -> *mut CpuTss {
+    let tss = new_tss();
+    load(Box::leak(Box::new(build(&tss.tss))));
+    tss
+}
+
+fn current_tss() -> *mut CpuTss {
+    // Before SMP is up only the BSP runs, and its local APIC may not be mapped yet
+    let tss = match smp::cpus().is_empty() {
+        true => 0,
+        false => smp::cpus()[smp::cpu_id()].tss.load(Ordering::SeqCst),
+    };
+    match tss {
+        0 => BSP_TSS.load(Ordering::SeqCst),
+        tss => tss as *mut CpuTss,
+    }
+}
+
+/// Runs `f` with the calling CPU's `index` interrupt stack moved `depth` bytes further down,
+/// so an exception nested in `f` gets fresh stack instead of landing on the running handler.
+/// Returns `None` without running `f` when that would leave the stack it was given.
+pub fn nested_ist<R>(index: u16, depth: u64, f: impl FnOnce() -> R) -> Option<R> {
+    let tss = current_tss();
+    if tss.is_null() {
+        return Some(f());
+    }
+
+    let (slot, bottom) = unsafe {
+        (
+            &mut (*tss).tss.interrupt_stack_table[index as usize],
+            (*tss).ist_bottom[index as usize],
+        )
+    };
+    let top = *slot;
+    // The handler for the nested fault runs below the new top, it needs a whole `depth` too
+    if top.as_u64() < bottom.as_u64() + 2 * depth {
+        return None;
+    }
+
+    *slot = top - depth;
+    let result = f();
+    *slot = top;
+    Some(result)
+}
@@ -0,0 +1,79 @@
+use super::{
+    acpi,
+    mem::vmm::{self, CacheMode},
+};
+
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::PhysAddr;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_COUNTER: usize = 0x0F0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+
+fn read(reg: usize) -> u64 {
+    unsafe { ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *const u64) }
+}
+
+fn write(reg: usize, value: u64) {
+    unsafe {
+        ptr::write_volatile(
+            (BASE.load(Ordering::Relaxed) as usize + reg) as *mut u64,
+            value,
+        )
+    }
+}
+
+/// Maps the HPET described by ACPI and starts its main counter.
+pub fn init() -> Result<(), &'static str> {
+    let hpet = acpi::info().hpet.ok_or("No HPET")?;
+    let regs = vmm::map_phys(
+        PhysAddr::new(hpet.address),
+        0x400,
+        CacheMode::Uncached,
+        "hpet",
+    )?;
+    BASE.store(regs.addr().as_u64(), Ordering::SeqCst);
+    // The registers stay mapped for good
+    mem::forget(regs);
+
+    let period = read(REG_CAPABILITIES) >> 32;
+    if period == 0 || period > 100_000_000 {
+        return Err("HPET reports a bogus period");
+    }
+    PERIOD_FS.store(period, Ordering::SeqCst);
+    COUNTER_64BIT.store(hpet.counter_64bit, Ordering::SeqCst);
+
+    write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+    Ok(())
+}
+
+pub fn available() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// Whether the counter is wide enough to never wrap while the kernel runs.
+pub fn counter_64bit() -> bool {
+    COUNTER_64BIT.load(Ordering::Relaxed)
+}
+
+pub fn counter() -> u64 {
+    read(REG_COUNTER)
+}
+
+pub fn period_fs() -> u64 {
+    PERIOD_FS.load(Ordering::Relaxed)
+}
+
+pub fn nanos() -> u64 {
+    (counter() as u128 * period_fs() as u128 / FEMTOS_PER_NANO as u128) as u64
+}
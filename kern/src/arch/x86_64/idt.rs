@@ -1,74 +1,185 @@
+use crate::schema::sys::SysSchema;
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use spin::RwLock;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable};
+
+pub const FIRST_EXTERNAL_VECTOR: u8 = 0x20;
+pub const DYNAMIC_VECTOR_BASE: u8 = 0x30;
+
+pub type Handler = Box<dyn Fn() + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(usize);
+
+struct Registration {
+    id: HandlerId,
+    name: &'static str,
+    handler: Handler,
+}
 
-use super::pic::{InterruptIndex, PICS};
+struct Vector {
+    handlers: RwLock<Vec<Registration>>,
+    count: AtomicU64,
+}
 
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        super::exception::install(&mut idt);
 
-        idt[InterruptIndex::Timer.into()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.into()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::Mouse.into()]
-            .set_handler_fn(super::task::mouse::mouse_interrupt_handler);
+        for (i, stub) in VECTOR_STUBS.iter().flatten().enumerate() {
+            idt[FIRST_EXTERNAL_VECTOR as usize + i].set_handler_fn(*stub);
+        }
 
         idt
     };
+    static ref VECTORS: Vec<Vector> = (FIRST_EXTERNAL_VECTOR..=255)
+        .map(|_| Vector {
+            handlers: RwLock::new(Vec::new()),
+            count: AtomicU64::new(0),
+        })
+        .collect();
 }
 
-pub fn init() {
-    IDT.load();
-    print!("IDT loaded");
-    ok!();
+fn vector_entry(vector: u8) -> &'static Vector {
+    assert!(vector >= FIRST_EXTERNAL_VECTOR);
+    &VECTORS[(vector - FIRST_EXTERNAL_VECTOR) as usize]
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+fn next_id() -> HandlerId {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Chains `handler` onto `vector`. Every handler on a vector runs for each interrupt.
+pub fn register(
+    vector: u8,
+    name: &'static str,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<HandlerId, &'static str> {
+    if vector < FIRST_EXTERNAL_VECTOR || vector == super::lapic::SPURIOUS_VECTOR {
+        return Err("Vector is reserved");
+    }
+
+    let id = next_id();
+    let handler: Handler = Box::new(handler);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        vector_entry(vector)
+            .handlers
+            .write()
+            .push(Registration { id, name, handler });
+    });
+    Ok(id)
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    super::hlt_loop();
+/// Removes a handler, returning how many are still chained on `vector`.
+pub fn unregister(vector: u8, id: HandlerId) -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = vector_entry(vector).handlers.write();
+        handlers.retain(|reg| reg.id != id);
+        handlers.len()
+    })
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.into());
-    }
+/// Hands out an unused vector with `handler` as its only handler.
+pub fn allocate_vector(
+    name: &'static str,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Option<u8> {
+    let handler: Handler = Box::new(handler);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for vector in DYNAMIC_VECTOR_BASE..super::lapic::SPURIOUS_VECTOR {
+            let mut handlers = vector_entry(vector).handlers.write();
+            if handlers.is_empty() {
+                handlers.push(Registration {
+                    id: next_id(),
+                    name,
+                    handler,
+                });
+                return Some(vector);
+            }
+        }
+        None
+    })
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+pub fn free_vector(vector: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        vector_entry(vector).handlers.write().clear();
+    });
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    super::task::keyboard::add_scancode(scancode);
+fn dispatch(vector: u8) {
+    let entry = vector_entry(vector);
+    entry.count.fetch_add(1, Ordering::Relaxed);
+    for reg in entry.handlers.read().iter() {
+        (reg.handler)();
+    }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.into());
+    if vector != super::lapic::SPURIOUS_VECTOR {
+        super::irq::eoi(vector);
     }
 }
+
+pub fn register_sys(sys: &mut SysSchema) {
+    sys.insert_text("interrupts", || {
+        let mut out = String::from("VECTOR      COUNT  HANDLERS\n");
+        for (i, entry) in VECTORS.iter().enumerate() {
+            let count = entry.count.load(Ordering::Relaxed);
+            let handlers = entry.handlers.read();
+            if count == 0 && handlers.is_empty() {
+                continue;
+            }
+
+            let names: Vec<&str> = handlers.iter().map(|reg| reg.name).collect();
+            out += &format!(
+                "0x{:02x}  {:>12}  {}\n",
+                FIRST_EXTERNAL_VECTOR as usize + i,
+                count,
+                names.join(", ")
+            );
+        }
+        out
+    });
+}
+
+macro_rules! vector_stubs {
+    ($($block:ident => $base:literal),* $(,)?) => {
+        $(
+            mod $block {
+                use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+                vector_stubs!(@block $base;
+                    s0 0, s1 1, s2 2, s3 3, s4 4, s5 5, s6 6, s7 7,
+                    s8 8, s9 9, s10 10, s11 11, s12 12, s13 13, s14 14, s15 15);
+            }
+        )*
+
+        const VECTOR_STUBS: [[HandlerFunc; 16]; 14] = [$($block::STUBS),*];
+    };
+    (@block $base:literal; $($name:ident $off:literal),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                super::dispatch($base + $off);
+            }
+        )*
+
+        pub const STUBS: [HandlerFunc; 16] = [$($name),*];
+    };
+}
+
+vector_stubs! {
+    vectors_2x => 0x20, vectors_3x => 0x30, vectors_4x => 0x40, vectors_5x => 0x50,
+    vectors_6x => 0x60, vectors_7x => 0x70, vectors_8x => 0x80, vectors_9x => 0x90,
+    vectors_ax => 0xA0, vectors_bx => 0xB0, vectors_cx => 0xC0, vectors_dx => 0xD0,
+    vectors_ex => 0xE0, vectors_fx => 0xF0,
+}
+
+pub fn init() {
+    IDT.load();
+    print!("IDT loaded");
+    ok!();
+}
@@ -0,0 +1,158 @@
+use super::{
+    acpi::madt,
+    mem::vmm::{self, CacheMode, Mapping},
+};
+
+use alloc::vec::Vec;
+use core::ptr;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::PhysAddr;
+
+#[allow(unused)]
+mod registers {
+    pub const IOREGSEL: u64 = 0x00;
+    pub const IOWIN: u64 = 0x10;
+
+    pub const ID: u32 = 0x00;
+    pub const VERSION: u32 = 0x01;
+    pub const REDIRECTION_TABLE: u32 = 0x10;
+
+    pub const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+    pub const REDIR_LEVEL: u64 = 1 << 15;
+    pub const REDIR_MASKED: u64 = 1 << 16;
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub gsi_base: u32,
+    pub entries: u32,
+    regs: Mapping,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub vector: u8,
+    pub dest_apic: u8,
+    pub active_low: bool,
+    pub level: bool,
+}
+
+lazy_static! {
+    static ref IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+}
+
+impl IoApic {
+    fn new(entry: &madt::IoApic) -> Result<Self, &'static str> {
+        let regs = vmm::map_phys(
+            PhysAddr::new(entry.address as u64),
+            0x20,
+            CacheMode::Uncached,
+            "ioapic",
+        )?;
+
+        let mut ioapic = Self {
+            id: entry.id,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+            regs,
+        };
+        ioapic.entries = ((ioapic.read(registers::VERSION) >> 16) & 0xFF) + 1;
+        Ok(ioapic)
+    }
+
+    fn reg_ptr(&self, offset: u64) -> *mut u32 {
+        (self.regs.addr().as_u64() + offset) as *mut u32
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.reg_ptr(registers::IOREGSEL), reg);
+            ptr::read_volatile(self.reg_ptr(registers::IOWIN))
+        }
+    }
+
+    fn write(&mut self, reg: u32, val: u32) {
+        unsafe {
+            ptr::write_volatile(self.reg_ptr(registers::IOREGSEL), reg);
+            ptr::write_volatile(self.reg_ptr(registers::IOWIN), val);
+        }
+    }
+
+    fn read_redirection(&self, pin: u32) -> u64 {
+        let reg = registers::REDIRECTION_TABLE + pin * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_redirection(&mut self, pin: u32, val: u64) {
+        let reg = registers::REDIRECTION_TABLE + pin * 2;
+        // Mask first so the entry never fires half-written
+        self.write(reg, registers::REDIR_MASKED as u32);
+        self.write(reg + 1, (val >> 32) as u32);
+        self.write(reg, val as u32);
+    }
+}
+
+pub fn init(madt: &madt::Madt) -> usize {
+    let mut ioapics = IOAPICS.lock();
+    for entry in madt.io_apics.iter() {
+        let mut ioapic = match IoApic::new(entry) {
+            Ok(ioapic) => ioapic,
+            Err(e) => {
+                error!("IOAPIC {}: {}", entry.id, e);
+                continue;
+            }
+        };
+        for pin in 0..ioapic.entries {
+            ioapic.write_redirection(pin, registers::REDIR_MASKED);
+        }
+        info!(
+            "IOAPIC {}: GSIs {}-{}",
+            ioapic.id,
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.entries - 1
+        );
+        ioapics.push(ioapic);
+    }
+    ioapics.len()
+}
+
+pub fn set_route(gsi: u32, route: Route) -> Result<(), &'static str> {
+    let mut ioapics = IOAPICS.lock();
+    let ioapic = ioapics
+        .iter_mut()
+        .find(|ioapic| ioapic.handles(gsi))
+        .ok_or("No IOAPIC handles this GSI")?;
+
+    let mut entry = route.vector as u64 | (route.dest_apic as u64) << 56;
+    if route.active_low {
+        entry |= registers::REDIR_ACTIVE_LOW;
+    }
+    if route.level {
+        entry |= registers::REDIR_LEVEL;
+    }
+    let pin = gsi - ioapic.gsi_base;
+    ioapic.write_redirection(pin, entry);
+    Ok(())
+}
+
+pub fn set_masked(gsi: u32, masked: bool) -> Result<(), &'static str> {
+    let mut ioapics = IOAPICS.lock();
+    let ioapic = ioapics
+        .iter_mut()
+        .find(|ioapic| ioapic.handles(gsi))
+        .ok_or("No IOAPIC handles this GSI")?;
+
+    let pin = gsi - ioapic.gsi_base;
+    let entry = ioapic.read_redirection(pin);
+    if masked {
+        ioapic.write_redirection(pin, entry | registers::REDIR_MASKED);
+    } else {
+        ioapic.write_redirection(pin, entry & !registers::REDIR_MASKED);
+    }
+    Ok(())
+}
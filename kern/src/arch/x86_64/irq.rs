@@ -0,0 +1,185 @@
+use super::{
+    acpi,
+    idt::{self, HandlerId},
+    ioapic, lapic, pic,
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    // The PICs are remapped either way so stray legacy interrupts never land on exception vectors
+    pic::init();
+    lapic::init().expect("Failed to map the local APIC");
+
+    let madt = match &acpi::info().madt {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => {
+            print!("No IOAPIC, using PIC routing");
+            ok!();
+            return;
+        }
+    };
+
+    pic::disable();
+    ioapic::init(madt);
+    APIC_MODE.store(true, Ordering::SeqCst);
+
+    let apic_id = lapic::id() as u32;
+    let processor = madt.processors.iter().find(|cpu| cpu.apic_id == apic_id);
+    for nmi in madt.nmis.iter() {
+        let ours = nmi.processor_id == 0xFF
+            || processor.map_or(false, |cpu| cpu.processor_id == nmi.processor_id as u32);
+        if ours {
+            lapic::set_nmi(
+                nmi.lint,
+                nmi.flags & 0x3 == 0x3,
+                (nmi.flags >> 2) & 0x3 == 0x3,
+            );
+        }
+    }
+}
+
+pub fn apic_enabled() -> bool {
+    APIC_MODE.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Resolves a legacy IRQ line through the MADT overrides, returning (gsi, active_low, level).
+/// Overrides that conform to the bus keep the trigger and polarity of the line itself.
+fn line_to_gsi(irq: u8, trigger: Trigger, polarity: Polarity) -> (u32, bool, bool) {
+    let mut active_low = polarity == Polarity::ActiveLow;
+    let mut level = trigger == Trigger::Level;
+    let over = acpi::info().madt.as_ref().and_then(|madt| {
+        madt.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .copied()
+    });
+
+    match over {
+        Some(o) => {
+            if o.flags & 0x3 != 0 {
+                active_low = o.active_low();
+            }
+            if (o.flags >> 2) & 0x3 != 0 {
+                level = o.level_triggered();
+            }
+            (o.gsi, active_low, level)
+        }
+        None => (irq as u32, active_low, level),
+    }
+}
+
+fn isa_to_gsi(irq: u8) -> u32 {
+    line_to_gsi(irq, Trigger::Edge, Polarity::ActiveHigh).0
+}
+
+/// Delivers `irq` on `vector`. The PIC can only use the vector it was remapped to.
+pub fn route(
+    irq: u8,
+    vector: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> Result<(), &'static str> {
+    if !apic_enabled() {
+        if vector != pic::PIC_1_OFFS + irq {
+            return Err("PIC routing is fixed");
+        }
+        pic::unmask(irq);
+        return Ok(());
+    }
+
+    let (gsi, active_low, level) = line_to_gsi(irq, trigger, polarity);
+    ioapic::set_route(
+        gsi,
+        ioapic::Route {
+            vector,
+            dest_apic: lapic::id(),
+            active_low,
+            level,
+        },
+    )
+}
+
+/// Routes an ISA `irq` to its legacy vector, the one the PIC would have used.
+pub fn enable(irq: u8) -> Result<(), &'static str> {
+    route(
+        irq,
+        pic::PIC_1_OFFS + irq,
+        Trigger::Edge,
+        Polarity::ActiveHigh,
+    )
+}
+
+pub fn mask(irq: u8) {
+    if apic_enabled() {
+        ioapic::set_masked(isa_to_gsi(irq), true).ok();
+    } else {
+        pic::mask(irq);
+    }
+}
+
+pub fn unmask(irq: u8) {
+    if apic_enabled() {
+        ioapic::set_masked(isa_to_gsi(irq), false).ok();
+    } else {
+        pic::unmask(irq);
+    }
+}
+
+/// Chains `handler` onto an ISA IRQ line, routing and unmasking it for the first handler.
+pub fn register(
+    irq: u8,
+    name: &'static str,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<HandlerId, &'static str> {
+    register_with(irq, name, Trigger::Edge, Polarity::ActiveHigh, handler)
+}
+
+/// Like `register`, for lines that aren't edge triggered and active high, such as PCI INTx.
+pub fn register_with(
+    irq: u8,
+    name: &'static str,
+    trigger: Trigger,
+    polarity: Polarity,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<HandlerId, &'static str> {
+    let vector = pic::PIC_1_OFFS + irq;
+    let id = idt::register(vector, name, handler)?;
+    if let Err(e) = route(irq, vector, trigger, polarity) {
+        idt::unregister(vector, id);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Drops a handler from a legacy IRQ line, masking the line once nobody is left on it.
+pub fn unregister(irq: u8, id: HandlerId) {
+    if idt::unregister(pic::PIC_1_OFFS + irq, id) == 0 {
+        mask(irq);
+    }
+}
+
+pub fn eoi(vector: u8) {
+    let from_pic = vector >= pic::PIC_1_OFFS && vector < pic::PIC_2_OFFS + 8;
+    if apic_enabled() || !from_pic {
+        lapic::eoi();
+    } else {
+        unsafe {
+            pic::PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use super::mem::vmm::{self, CacheMode, Mapping};
+
+use core::ptr;
+use lazy_static::lazy_static;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+#[allow(unused)]
+mod registers {
+    pub const ID: u32 = 0x020;
+    pub const TPR: u32 = 0x080;
+    pub const EOI: u32 = 0x0B0;
+    pub const SVR: u32 = 0x0F0;
+    pub const ESR: u32 = 0x280;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+
+    pub const SVR_ENABLE: u32 = 1 << 8;
+
+    pub const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+    pub const LVT_ACTIVE_LOW: u32 = 1 << 13;
+    pub const LVT_LEVEL: u32 = 1 << 15;
+    pub const LVT_MASKED: u32 = 1 << 16;
+
+    pub const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+    pub const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+    pub const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+    pub const ICR_PENDING: u32 = 1 << 12;
+    pub const ICR_ASSERT: u32 = 1 << 14;
+    pub const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+}
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+lazy_static! {
+    // Every CPU sees its own local APIC at the same address, so one mapping serves them all
+    static ref LAPIC: Result<Mapping, &'static str> = {
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & 0xFFFF_F000;
+        vmm::map_phys(PhysAddr::new(base), 0x1000, CacheMode::Uncached, "lapic")
+    };
+}
+
+pub fn init() -> Result<(), &'static str> {
+    LAPIC.as_ref().map_err(|e| *e)?;
+    enable();
+    print!("LAPIC enabled (id: {})", id());
+    ok!();
+    Ok(())
+}
+
+/// Brings up the local APIC of the calling CPU.
+pub fn enable() {
+    // Nothing is wired to the LVTs until someone asks for it
+    write(registers::LVT_TIMER, registers::LVT_MASKED);
+    write(registers::LVT_LINT0, registers::LVT_MASKED);
+    write(registers::LVT_LINT1, registers::LVT_MASKED);
+    write(registers::LVT_ERROR, registers::LVT_MASKED);
+    write(registers::ESR, 0);
+    write(registers::TPR, 0);
+
+    write(
+        registers::SVR,
+        read(registers::SVR) | registers::SVR_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+pub fn id() -> u8 {
+    (read(registers::ID) >> 24) as u8
+}
+
+/// Wires local interrupt pin `lint` as the NMI input, as described by the MADT.
+pub fn set_nmi(lint: u8, active_low: bool, level: bool) {
+    let reg = match lint {
+        0 => registers::LVT_LINT0,
+        _ => registers::LVT_LINT1,
+    };
+    let mut val = registers::LVT_DELIVERY_NMI;
+    if active_low {
+        val |= registers::LVT_ACTIVE_LOW;
+    }
+    if level {
+        val |= registers::LVT_LEVEL;
+    }
+    write(reg, val);
+}
+
+fn send_icr(dest: u8, command: u32) {
+    write(registers::ICR_HIGH, (dest as u32) << 24);
+    write(registers::ICR_LOW, command);
+    while read(registers::ICR_LOW) & registers::ICR_PENDING != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_icr(apic_id, vector as u32);
+}
+
+pub fn broadcast_ipi(vector: u8) {
+    send_icr(0, registers::ICR_ALL_EXCLUDING_SELF | vector as u32);
+}
+
+/// NMIs get through to CPUs running with interrupts disabled, unlike vectored IPIs.
+pub fn broadcast_nmi() {
+    send_icr(
+        0,
+        registers::ICR_ALL_EXCLUDING_SELF | registers::ICR_DELIVERY_NMI,
+    );
+}
+
+pub fn send_init(apic_id: u8) {
+    send_icr(
+        apic_id,
+        registers::ICR_DELIVERY_INIT | registers::ICR_ASSERT,
+    );
+}
+
+/// Starts `apic_id` executing in real mode at physical address `page << 12`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_icr(
+        apic_id,
+        registers::ICR_DELIVERY_STARTUP | registers::ICR_ASSERT | page as u32,
+    );
+}
+
+pub fn eoi() {
+    write(registers::EOI, 0);
+}
+
+fn reg_ptr(reg: u32) -> *mut u32 {
+    let regs = LAPIC.as_ref().expect("LAPIC is not mapped");
+    (regs.addr().as_u64() + reg as u64) as *mut u32
+}
+
+fn read(reg: u32) -> u32 {
+    unsafe { ptr::read_volatile(reg_ptr(reg)) }
+}
+
+fn write(reg: u32, val: u32) {
+    unsafe { ptr::write_volatile(reg_ptr(reg), val) }
+}
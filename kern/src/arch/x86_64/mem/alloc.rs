@@ -1,5 +1,14 @@
-use linked_list_allocator::LockedHeap;
+use super::fault;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
@@ -7,17 +16,89 @@ use x86_64::{
 };
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 16 * 1024 * 1024;
+pub const HEAP_INITIAL_SIZE: usize = 4 * 1024 * 1024;
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+const HEAP_GROW_STEP: usize = 1024 * 1024;
 
-pub fn init() -> Result<(), MapToError<Size4KiB>> {
-    use crate::{FRAME_ALLOC, MAPPER};
+const SLAB_SIZE: usize = 4096;
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+struct SlabCache {
+    free: Option<NonNull<FreeBlock>>,
+    slabs: usize,
+    in_use: usize,
+}
+
+// Blocks are only ever touched with the cache's lock held
+unsafe impl Send for SlabCache {}
+
+pub struct KernelHeap {
+    heap: Mutex<Heap>,
+    caches: [Mutex<SlabCache>; SIZE_CLASSES.len()],
+    requested: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub peak: usize,
+    pub requested: usize,
+    pub slab_bytes: usize,
+    pub slab_free_bytes: usize,
+}
+
+impl HeapStats {
+    /// Share of used heap memory that is not backing a live allocation, in percent.
+    pub fn fragmentation(&self) -> usize {
+        match self.used {
+            0 => 0,
+            used => used.saturating_sub(self.requested) * 100 / used,
+        }
+    }
+}
+
+macro_rules! slab_cache {
+    () => {
+        Mutex::new(SlabCache {
+            free: None,
+            slabs: 0,
+            in_use: 0,
+        })
+    };
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap {
+    heap: Mutex::new(Heap::empty()),
+    caches: [
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+        slab_cache!(),
+    ],
+    requested: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+fn map_heap(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    use crate::{FRAME_ALLOC, KERNEL_SPACE};
 
     let mut falloc = FRAME_ALLOC.wait().lock();
-    let mut mapper = MAPPER.wait().lock();
+    let mut mapper = KERNEL_SPACE.wait().lock();
 
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
 
@@ -32,15 +113,155 @@ pub fn init() -> Result<(), MapToError<Size4KiB>> {
         unsafe { mapper.map_to(page, frame, flags, &mut *falloc)?.flush() };
     }
 
+    Ok(())
+}
+
+pub fn init() -> Result<(), MapToError<Size4KiB>> {
+    // Early boot runs without a page fault handler, so the initial heap is mapped up front
+    map_heap(HEAP_START, HEAP_INITIAL_SIZE)?;
+    fault::register_lazy(
+        VirtAddr::new((HEAP_START + HEAP_INITIAL_SIZE) as u64),
+        VirtAddr::new((HEAP_START + HEAP_MAX_SIZE) as u64),
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        "heap",
+    )
+    .map_err(|_| MapToError::FrameAllocationFailed)?;
+
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.heap.lock().init(HEAP_START, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
 }
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|class| size <= *class)
+}
+
+impl KernelHeap {
+    /// Extends the heap far enough to satisfy `layout`. The new pages are backed on first touch.
+    fn grow(heap: &mut Heap, layout: &Layout) -> bool {
+        let needed = layout.size() + layout.align();
+        let by = (needed.max(HEAP_GROW_STEP) + SLAB_SIZE - 1) & !(SLAB_SIZE - 1);
+        if heap.top() + by > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+
+        unsafe { heap.extend(by) };
+        true
+    }
+
+    fn heap_alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        loop {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if !Self::grow(&mut heap, &layout) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    fn slab_alloc(&self, class: usize) -> *mut u8 {
+        let mut cache = self.caches[class].lock();
+        if cache.free.is_none() {
+            let slab = self.heap_alloc(Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap());
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+
+            let block_size = SIZE_CLASSES[class];
+            for offset in (0..SLAB_SIZE).step_by(block_size).rev() {
+                let block = unsafe { slab.add(offset) } as *mut FreeBlock;
+                unsafe { block.write(FreeBlock { next: cache.free }) };
+                cache.free = NonNull::new(block);
+            }
+            cache.slabs += 1;
+        }
+
+        let block = cache.free.unwrap();
+        cache.free = unsafe { block.as_ref().next };
+        cache.in_use += 1;
+        block.as_ptr() as *mut u8
+    }
+
+    fn slab_free(&self, class: usize, ptr: *mut u8) {
+        let mut cache = self.caches[class].lock();
+        let block = ptr as *mut FreeBlock;
+        unsafe { block.write(FreeBlock { next: cache.free }) };
+        cache.free = NonNull::new(block);
+        cache.in_use -= 1;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        interrupts::without_interrupts(|| {
+            let (size, used) = {
+                let heap = self.heap.lock();
+                (heap.size(), heap.used())
+            };
+
+            let mut slab_bytes = 0;
+            let mut slab_free_bytes = 0;
+            for (class, cache) in self.caches.iter().enumerate() {
+                let cache = cache.lock();
+                slab_bytes += cache.slabs * SLAB_SIZE;
+                slab_free_bytes += cache.slabs * SLAB_SIZE - cache.in_use * SIZE_CLASSES[class];
+            }
+
+            HeapStats {
+                size,
+                used,
+                peak: self.peak.load(Ordering::Relaxed),
+                requested: self.requested.load(Ordering::Relaxed),
+                slab_bytes,
+                slab_free_bytes,
+            }
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Interrupt handlers allocate too, they must never find the heap locked under them
+        let ptr = interrupts::without_interrupts(|| match size_class(&layout) {
+            Some(class) => self.slab_alloc(class),
+            None => self.heap_alloc(layout),
+        });
+
+        if !ptr.is_null() {
+            self.requested.fetch_add(layout.size(), Ordering::Relaxed);
+            let used = interrupts::without_interrupts(|| self.heap.lock().used());
+            self.peak.fetch_max(used, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| match size_class(&layout) {
+            Some(class) => self.slab_free(class, ptr),
+            None => self
+                .heap
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout),
+        });
+        self.requested.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Whether the heap or any of its slab caches is locked, on any CPU.
+pub fn is_locked() -> bool {
+    ALLOCATOR.heap.try_lock().is_none()
+        || ALLOCATOR
+            .caches
+            .iter()
+            .any(|cache| cache.try_lock().is_none())
+}
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -0,0 +1,340 @@
+use super::paging::{active_l4_table, phys_to_virt};
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+use spinning::Mutex;
+use x86_64::{
+    structures::paging::{MapperAllSizes, OffsetPageTable, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: usize = 4096;
+
+/// For devices that can only generate 32-bit addresses.
+pub const DMA_LIMIT_32: u64 = 1 << 32;
+pub const DMA_LIMIT_NONE: u64 = 1 << 52;
+
+fn frames_for(size: usize) -> usize {
+    ((size + FRAME_SIZE - 1) / FRAME_SIZE).max(1)
+}
+
+/// Grabs zeroed, physically contiguous frames aligned to `align` bytes and ending below `limit`.
+fn alloc_frames(frames: usize, align: usize, limit: u64) -> Result<PhysAddr, &'static str> {
+    let align = frames_for(align);
+    let frame = crate::FRAME_ALLOC
+        .wait()
+        .lock()
+        .allocate_contiguous(frames, align, PhysAddr::new(limit))
+        .ok_or("No physically contiguous memory left for DMA")?;
+
+    let phys = frame.start_address();
+    unsafe {
+        ptr::write_bytes(
+            phys_to_virt(phys).as_mut_ptr::<u8>(),
+            0,
+            frames * FRAME_SIZE,
+        )
+    };
+    Ok(phys)
+}
+
+unsafe fn free_frames(phys: PhysAddr, frames: usize) {
+    crate::FRAME_ALLOC
+        .wait()
+        .lock()
+        .deallocate_contiguous(PhysFrame::containing_address(phys), frames);
+}
+
+/// A value living in physically contiguous memory a device can be pointed at.
+pub struct DmaBuffer<T: ?Sized> {
+    ptr: NonNull<T>,
+    phys: PhysAddr,
+    frames: usize,
+}
+
+unsafe impl<T: ?Sized + Send> Send for DmaBuffer<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    pub fn new(value: T) -> Result<Self, &'static str> {
+        Self::with_limits(value, mem::align_of::<T>(), DMA_LIMIT_NONE)
+    }
+
+    /// Places `value` at an `align` byte boundary entirely below the physical address `limit`.
+    pub fn with_limits(value: T, align: usize, limit: u64) -> Result<Self, &'static str> {
+        let frames = frames_for(mem::size_of::<T>());
+        let phys = alloc_frames(frames, align.max(mem::align_of::<T>()), limit)?;
+
+        let ptr = phys_to_virt(phys).as_mut_ptr::<T>();
+        unsafe { ptr.write(value) };
+        Ok(Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            phys,
+            frames,
+        })
+    }
+}
+
+impl<T: Copy> DmaBuffer<[T]> {
+    pub fn new_slice(len: usize, value: T) -> Result<Self, &'static str> {
+        Self::slice_with_limits(len, value, mem::align_of::<T>(), DMA_LIMIT_NONE)
+    }
+
+    pub fn slice_with_limits(
+        len: usize,
+        value: T,
+        align: usize,
+        limit: u64,
+    ) -> Result<Self, &'static str> {
+        let frames = frames_for(len * mem::size_of::<T>());
+        let phys = alloc_frames(frames, align.max(mem::align_of::<T>()), limit)?;
+
+        let ptr = phys_to_virt(phys).as_mut_ptr::<T>();
+        for i in 0..len {
+            unsafe { ptr.add(i).write(value) };
+        }
+        Ok(Self {
+            ptr: NonNull::new(unsafe { slice::from_raw_parts_mut(ptr, len) }).unwrap(),
+            phys,
+            frames,
+        })
+    }
+}
+
+impl<T: ?Sized> DmaBuffer<T> {
+    /// The address to program into the device.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        phys_to_virt(self.phys)
+    }
+
+    pub fn size(&self) -> usize {
+        mem::size_of_val(&**self)
+    }
+
+    pub fn sg_list(&self) -> SgList {
+        let mut list = SgList::new();
+        list.push(self.phys, self.size());
+        list
+    }
+}
+
+impl<T: ?Sized> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            free_frames(self.phys, self.frames);
+        }
+    }
+}
+
+struct PoolInner {
+    block_size: usize,
+    limit: u64,
+    chunks: Vec<PhysAddr>,
+    free: Vec<PhysAddr>,
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        for chunk in self.chunks.drain(..) {
+            unsafe { free_frames(chunk, 1) };
+        }
+    }
+}
+
+/// Hands out small fixed-size DMA blocks, such as descriptors or command structures.
+/// Blocks never cross a page boundary.
+#[derive(Clone)]
+pub struct DmaPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+impl DmaPool {
+    pub fn new(block_size: usize, align: usize, limit: u64) -> Result<Self, &'static str> {
+        let align = align.max(1).next_power_of_two();
+        let block_size = (block_size.max(1) + align - 1) / align * align;
+        if block_size > FRAME_SIZE {
+            return Err("DMA pool blocks must fit in a page");
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(PoolInner {
+                block_size,
+                limit,
+                chunks: Vec::new(),
+                free: Vec::new(),
+            })),
+        })
+    }
+
+    pub fn alloc(&self) -> Result<DmaBlock, &'static str> {
+        let mut inner = self.inner.lock();
+        if inner.free.is_empty() {
+            let chunk = alloc_frames(1, FRAME_SIZE, inner.limit)?;
+            inner.chunks.push(chunk);
+            let block_size = inner.block_size;
+            for offset in (0..=FRAME_SIZE - block_size).step_by(block_size).rev() {
+                inner.free.push(chunk + offset);
+            }
+        }
+
+        let phys = inner.free.pop().unwrap();
+        let size = inner.block_size;
+        unsafe { ptr::write_bytes(phys_to_virt(phys).as_mut_ptr::<u8>(), 0, size) };
+        Ok(DmaBlock {
+            pool: self.inner.clone(),
+            phys,
+            size,
+        })
+    }
+}
+
+/// A block borrowed from a `DmaPool`, returned to it when dropped.
+pub struct DmaBlock {
+    pool: Arc<Mutex<PoolInner>>,
+    phys: PhysAddr,
+    size: usize,
+}
+
+impl DmaBlock {
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        phys_to_virt(self.phys)
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        assert!(mem::size_of::<T>() <= self.size);
+        self.virt().as_mut_ptr()
+    }
+}
+
+impl Deref for DmaBlock {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt().as_ptr(), self.size) }
+    }
+}
+
+impl DerefMut for DmaBlock {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt().as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBlock {
+    fn drop(&mut self) {
+        self.pool.lock().free.push(self.phys);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgEntry {
+    pub addr: PhysAddr,
+    pub len: usize,
+}
+
+/// The physical pieces making up a buffer, for devices that take a list of segments.
+#[derive(Debug, Clone, Default)]
+pub struct SgList {
+    entries: Vec<SgEntry>,
+}
+
+impl SgList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Describes a mapped kernel buffer, usually one that is only virtually contiguous.
+    /// The buffer must stay alive and in place for as long as the device uses the list.
+    pub fn from_buffer(buf: &[u8]) -> Result<Self, &'static str> {
+        // Read-only walk of the live tables, the list grows on the heap which may fault under
+        // `KERNEL_SPACE`
+        let offset = *crate::PHYS_MEM_OFFSET.wait();
+        let mapper = unsafe { OffsetPageTable::new(active_l4_table(offset), offset) };
+        let mut list = Self::new();
+
+        let mut addr = buf.as_ptr() as u64;
+        let end = addr + buf.len() as u64;
+        while addr < end {
+            let page_end = (addr & !(FRAME_SIZE as u64 - 1)) + FRAME_SIZE as u64;
+            let len = page_end.min(end) - addr;
+            let phys = mapper
+                .translate_addr(VirtAddr::new(addr))
+                .ok_or("Buffer is not mapped")?;
+            list.push(phys, len as usize);
+            addr += len;
+        }
+
+        Ok(list)
+    }
+
+    /// Appends a segment, merging it into the previous one when they are adjacent.
+    pub fn push(&mut self, addr: PhysAddr, len: usize) {
+        if let Some(last) = self.entries.last_mut() {
+            if last.addr + last.len == addr {
+                last.len += len;
+                return;
+            }
+        }
+        self.entries.push(SgEntry { addr, len });
+    }
+
+    pub fn entries(&self) -> &[SgEntry] {
+        &self.entries
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.entries.iter().map(|entry| entry.len).sum()
+    }
+
+    /// Whether a device limited to addresses below `limit` can reach every segment.
+    pub fn fits_below(&self, limit: u64) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.addr.as_u64() + entry.len as u64 <= limit)
+    }
+
+    /// Splits segments so none is longer than what the device accepts in a single descriptor.
+    pub fn split(&self, max_len: usize) -> Self {
+        let mut entries = Vec::new();
+        for entry in &self.entries {
+            let mut offset = 0;
+            while offset < entry.len {
+                let len = (entry.len - offset).min(max_len);
+                entries.push(SgEntry {
+                    addr: entry.addr + offset,
+                    len,
+                });
+                offset += len;
+            }
+        }
+        Self { entries }
+    }
+}
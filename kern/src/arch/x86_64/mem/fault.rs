@@ -0,0 +1,302 @@
+use super::{paging::phys_to_virt, space};
+use crate::arch::gdt;
+
+use alloc::boxed::Box;
+use core::{
+    ptr, slice, str,
+    sync::atomic::{spin_loop_hint, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+const MAX_REGIONS: usize = 128;
+
+const KIND_FREE: u8 = 0;
+const KIND_LAZY: u8 = 1;
+const KIND_GUARD: u8 = 2;
+const KIND_PAGED: u8 = 3;
+// Claimed by `register` but not published yet
+const KIND_CLAIMED: u8 = 0xFF;
+
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+
+/// Supplies the contents of a paged region, one page at a time.
+pub trait Pager: Send + Sync {
+    /// Fills `page` with the data found `offset` bytes into the region. It runs inside the page
+    /// fault handler but may touch lazily backed memory, e.g. allocate.
+    fn fill(&self, offset: u64, page: &mut [u8]) -> Result<(), &'static str>;
+}
+
+/// One entry of the region table. The table is read from the page fault handler, which may
+/// interrupt any lock holder, so it is made of atomics instead of living behind a lock.
+struct Slot {
+    kind: AtomicU8,
+    start: AtomicU64,
+    end: AtomicU64,
+    flags: AtomicU64,
+    name: AtomicPtr<u8>,
+    name_len: AtomicUsize,
+    // A leaked `Box<Box<dyn Pager>>` for paged regions, the outer box keeps the pointer thin
+    pager: AtomicPtr<Box<dyn Pager>>,
+    // Faults currently inside the pager, it is only freed once they are done
+    pager_users: AtomicUsize,
+}
+
+macro_rules! slot {
+    () => {
+        Slot {
+            kind: AtomicU8::new(KIND_FREE),
+            start: AtomicU64::new(0),
+            end: AtomicU64::new(0),
+            flags: AtomicU64::new(0),
+            name: AtomicPtr::new(ptr::null_mut()),
+            name_len: AtomicUsize::new(0),
+            pager: AtomicPtr::new(ptr::null_mut()),
+            pager_users: AtomicUsize::new(0),
+        }
+    };
+}
+
+macro_rules! slot_group {
+    () => {
+        [
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+            slot!(),
+        ]
+    };
+}
+
+static SLOTS: [[Slot; 16]; MAX_REGIONS / 16] = [
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+    slot_group!(),
+];
+
+fn slots() -> impl Iterator<Item = &'static Slot> {
+    SLOTS.iter().flat_map(|group| group.iter())
+}
+
+impl Slot {
+    fn name(&self) -> &'static str {
+        let ptr = self.name.load(Ordering::Acquire);
+        let len = self.name_len.load(Ordering::Acquire);
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.start.load(Ordering::Acquire) && addr < self.end.load(Ordering::Acquire)
+    }
+
+    fn is_region(&self) -> bool {
+        match self.kind.load(Ordering::Acquire) {
+            KIND_LAZY | KIND_GUARD | KIND_PAGED => true,
+            _ => false,
+        }
+    }
+}
+
+fn register(
+    kind: u8,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+    name: &'static str,
+    pager: *mut Box<dyn Pager>,
+) -> Result<(), &'static str> {
+    let slot = slots()
+        .find(|slot| {
+            slot.kind
+                .compare_and_swap(KIND_FREE, KIND_CLAIMED, Ordering::AcqRel)
+                == KIND_FREE
+        })
+        .ok_or("Fault region table is full")?;
+
+    slot.start.store(start.as_u64(), Ordering::Release);
+    slot.end.store(end.as_u64(), Ordering::Release);
+    slot.flags.store(flags.bits(), Ordering::Release);
+    slot.name.store(name.as_ptr() as *mut u8, Ordering::Release);
+    slot.name_len.store(name.len(), Ordering::Release);
+    slot.pager.store(pager, Ordering::Release);
+    slot.kind.store(kind, Ordering::Release);
+    Ok(())
+}
+
+/// Backs `start..end` with zeroed frames the first time each page is touched.
+pub fn register_lazy(
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<(), &'static str> {
+    register(KIND_LAZY, start, end, flags, name, ptr::null_mut())
+}
+
+/// Reports any access to `start..end` as an overflow of the stack called `name`.
+pub fn register_guard(
+    start: VirtAddr,
+    end: VirtAddr,
+    name: &'static str,
+) -> Result<(), &'static str> {
+    register(
+        KIND_GUARD,
+        start,
+        end,
+        PageTableFlags::empty(),
+        name,
+        ptr::null_mut(),
+    )
+}
+
+/// Backs `start..end` with pages filled by `pager` the first time each one is touched.
+pub fn register_paged(
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+    name: &'static str,
+    pager: Box<dyn Pager>,
+) -> Result<(), &'static str> {
+    let pager = Box::into_raw(Box::new(pager));
+    register(KIND_PAGED, start, end, flags, name, pager).map_err(|err| {
+        drop(unsafe { Box::from_raw(pager) });
+        err
+    })
+}
+
+/// Forgets the region starting at `start` and drops its pager once no fault is using it. Pages
+/// it already had backed stay mapped.
+pub fn unregister(start: VirtAddr) {
+    for slot in slots() {
+        if slot.is_region() && slot.start.load(Ordering::Acquire) == start.as_u64() {
+            let pager = slot.pager.swap(ptr::null_mut(), Ordering::SeqCst);
+            if !pager.is_null() {
+                while slot.pager_users.load(Ordering::SeqCst) != 0 {
+                    spin_loop_hint();
+                }
+                drop(unsafe { Box::from_raw(pager) });
+            }
+            slot.kind.store(KIND_FREE, Ordering::Release);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The page is mapped now, the faulting instruction can be retried.
+    Resolved,
+    StackOverflow(&'static str),
+    OutOfMemory(&'static str),
+    PagerFailed(&'static str, &'static str),
+    Unhandled,
+}
+
+/// Called for every page fault before it is reported. Code holding `FRAME_ALLOC` or
+/// `KERNEL_SPACE` must not touch lazily backed memory, resolving the fault needs both. Paged
+/// regions additionally need whatever locks their pager takes.
+pub fn resolve(addr: VirtAddr, error_code: u64) -> Resolution {
+    if error_code & (PF_PRESENT | PF_WRITE) == PF_PRESENT | PF_WRITE && space::resolve_cow(addr) {
+        return Resolution::Resolved;
+    }
+
+    let slot = match slots().find(|slot| slot.is_region() && slot.contains(addr.as_u64())) {
+        Some(slot) => slot,
+        None => return Resolution::Unhandled,
+    };
+
+    match slot.kind.load(Ordering::Acquire) {
+        KIND_GUARD => Resolution::StackOverflow(slot.name()),
+        _ if error_code & PF_PRESENT != 0 => Resolution::Unhandled,
+        kind => {
+            let page = Page::containing_address(addr);
+            let flags = PageTableFlags::from_bits_truncate(slot.flags.load(Ordering::Acquire));
+            let frame = match crate::FRAME_ALLOC.wait().lock().allocate_frame() {
+                Some(frame) => frame,
+                None => return Resolution::OutOfMemory(slot.name()),
+            };
+            let data = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+            unsafe { ptr::write_bytes(data, 0, 4096) };
+
+            if kind == KIND_PAGED {
+                let data = unsafe { slice::from_raw_parts_mut(data, 4096) };
+                if let Err(err) = fill_page(slot, page, data) {
+                    unsafe { crate::FRAME_ALLOC.wait().lock().deallocate_frame(frame) };
+                    return Resolution::PagerFailed(slot.name(), err);
+                }
+            }
+
+            match back_page(page, frame, flags) {
+                Ok(()) => Resolution::Resolved,
+                Err(()) => Resolution::OutOfMemory(slot.name()),
+            }
+        }
+    }
+}
+
+fn fill_page(slot: &Slot, page: Page<Size4KiB>, data: &mut [u8]) -> Result<(), &'static str> {
+    // Counted before the pager is read, `unregister` waits for the count after clearing it
+    slot.pager_users.fetch_add(1, Ordering::SeqCst);
+    let pager = slot.pager.load(Ordering::SeqCst);
+    let result = match pager.is_null() {
+        true => Err("Region was unregistered"),
+        false => {
+            let offset = page.start_address().as_u64() - slot.start.load(Ordering::Acquire);
+            // The pager may fault on the heap, which must not land on top of this handler's frame
+            gdt::nested_ist(
+                gdt::PAGE_FAULT_IST_INDEX,
+                gdt::PAGE_FAULT_NEST_DEPTH,
+                || unsafe { (*pager).fill(offset, data) },
+            )
+            .unwrap_or(Err("Page faults nested too deeply"))
+        }
+    };
+    slot.pager_users.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+fn back_page(page: Page<Size4KiB>, frame: PhysFrame, flags: PageTableFlags) -> Result<(), ()> {
+    use crate::{FRAME_ALLOC, KERNEL_SPACE};
+
+    let mut falloc = FRAME_ALLOC.wait().lock();
+    let mut mapper = KERNEL_SPACE.wait().lock();
+
+    let flags = flags | PageTableFlags::PRESENT;
+    match unsafe { mapper.map_to(page, frame, flags, &mut *falloc) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        // Another CPU got to the same page first
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            unsafe { falloc.deallocate_frame(frame) };
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { falloc.deallocate_frame(frame) };
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use super::paging::phys_to_virt;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Tracks every physical frame up to the highest usable address, one bit each, set when in use.
+/// Frames shared between address spaces also carry a count of their extra owners.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    shares: &'static mut [u16],
+    frames: usize,
+    usable: usize,
+    free: usize,
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    pub unsafe fn init(mem_map: &'static MemoryMap) -> Self {
+        let usable = || {
+            mem_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let frames = usable()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+        let words = (frames + 63) / 64;
+        let bitmap_bytes = (words * 8) as u64;
+        let shares_bytes = (frames * 2) as u64;
+        let bitmap_frames = ((bitmap_bytes + shares_bytes + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+
+        // The heap does not exist yet, so the bitmap lives in the first usable region that fits it
+        let home = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes + shares_bytes)
+            .expect("No usable region large enough for the frame bitmap")
+            .range
+            .start_addr();
+        let bitmap =
+            slice::from_raw_parts_mut(phys_to_virt(PhysAddr::new(home)).as_mut_ptr::<u64>(), words);
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let shares = slice::from_raw_parts_mut(
+            phys_to_virt(PhysAddr::new(home + bitmap_bytes)).as_mut_ptr::<u16>(),
+            frames,
+        );
+        for share in shares.iter_mut() {
+            *share = 0;
+        }
+
+        let mut alloc = Self {
+            bitmap,
+            shares,
+            frames,
+            usable: 0,
+            free: 0,
+            next: 0,
+        };
+        for region in usable() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for idx in start..end {
+                alloc.set_free(idx);
+            }
+        }
+        alloc.usable = alloc.free;
+
+        let home = (home / FRAME_SIZE) as usize;
+        for idx in home..home + bitmap_frames {
+            alloc.set_used(idx);
+        }
+        // Frame 0 is never handed out, a null physical address is too easy to mistake for none
+        if alloc.is_free(0) {
+            alloc.set_used(0);
+        }
+
+        alloc
+    }
+
+    fn is_free(&self, idx: usize) -> bool {
+        self.bitmap[idx / 64] & (1 << (idx % 64)) == 0
+    }
+
+    fn set_used(&mut self, idx: usize) {
+        debug_assert!(self.is_free(idx));
+        self.bitmap[idx / 64] |= 1 << (idx % 64);
+        self.free -= 1;
+    }
+
+    fn set_free(&mut self, idx: usize) {
+        debug_assert!(!self.is_free(idx));
+        self.bitmap[idx / 64] &= !(1 << (idx % 64));
+        self.free += 1;
+    }
+
+    fn frame(idx: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE))
+    }
+
+    fn limit_index(&self, limit: PhysAddr) -> usize {
+        ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frames)
+    }
+
+    /// Allocates a single frame that ends below `limit`, for devices that cannot address all of memory.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        self.allocate_contiguous(1, 1, limit)
+    }
+
+    /// Allocates `count` physically contiguous frames starting at a multiple of `align` frames,
+    /// entirely below `limit`.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrame> {
+        let align = align.max(1);
+        let end = self.limit_index(limit);
+
+        let mut start = 0;
+        while start + count <= end {
+            match (start..start + count).find(|idx| !self.is_free(*idx)) {
+                Some(used) => start = (used + 1 + align - 1) / align * align,
+                None => {
+                    for idx in start..start + count {
+                        self.set_used(idx);
+                    }
+                    return Some(Self::frame(start));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Returns `count` frames starting at `start` to the allocator. Shared frames only lose an owner.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = Self::index(start);
+        for idx in first..first + count {
+            match self.shares[idx] {
+                0 => self.set_free(idx),
+                _ => self.shares[idx] -= 1,
+            }
+        }
+        self.next = self.next.min(first / 64);
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Takes `frame` out of the allocator for good, failing if it is not a free usable frame.
+    pub fn reserve(&mut self, frame: PhysFrame) -> Result<(), &'static str> {
+        let idx = Self::index(frame);
+        if idx >= self.frames || !self.is_free(idx) {
+            return Err("Frame is already in use");
+        }
+        self.set_used(idx);
+        Ok(())
+    }
+
+    /// Adds an owner to an allocated frame, it is only freed once every owner gave it back.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let idx = Self::index(frame);
+        debug_assert!(!self.is_free(idx));
+        self.shares[idx] += 1;
+    }
+
+    /// How many owners `frame` has, zero when it is free.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let idx = Self::index(frame);
+        match idx < self.frames && !self.is_free(idx) {
+            true => self.shares[idx] as usize + 1,
+            false => 0,
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.usable - self.free
+    }
+
+    pub fn usable_frames(&self) -> usize {
+        self.usable
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let word = (self.next + i) % words;
+            let bits = self.bitmap[word];
+            if bits == !0 {
+                continue;
+            }
+
+            let idx = word * 64 + (!bits).trailing_zeros() as usize;
+            if idx >= self.frames {
+                continue;
+            }
+            self.set_used(idx);
+            self.next = word;
+            return Some(Self::frame(idx));
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1);
+    }
+}
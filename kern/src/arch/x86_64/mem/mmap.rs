@@ -0,0 +1,177 @@
+use super::{
+    fault::{self, Pager},
+    paging::phys_to_virt,
+    vmm,
+};
+use crate::arch::smp;
+
+use alloc::{boxed::Box, sync::Arc};
+use core::slice;
+use lib_kern::schema::file::File;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Mapper, Page, PageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+struct FilePager {
+    file: Arc<File>,
+    offset: usize,
+    len: usize,
+}
+
+impl Pager for FilePager {
+    fn fill(&self, offset: u64, page: &mut [u8]) -> Result<(), &'static str> {
+        let offset = offset as usize;
+        let len = self.len.saturating_sub(offset).min(page.len());
+        self.file
+            .read_at(self.offset + offset, &mut page[..len])
+            .map(|_| ())
+            .map_err(|_| "File read failed")
+    }
+}
+
+/// Walks the live tables without taking `KERNEL_SPACE`, the kernel half is shared by all of them.
+fn pte_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let mut table = unsafe { &*phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>() };
+    for idx in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+        let flags = table[*idx].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { &*phys_to_virt(table[*idx].addr()).as_ptr::<PageTable>() };
+    }
+
+    let flags = table[addr.p1_index()].flags();
+    Some(flags).filter(|flags| flags.contains(PageTableFlags::PRESENT))
+}
+
+/// A file mapped into the kernel address space. Pages are read from the schema on first touch,
+/// so code holding the schema lock must not access the mapping.
+pub struct FileMapping {
+    start: VirtAddr,
+    pages: u64,
+    file: Arc<File>,
+    offset: usize,
+    len: usize,
+    writable: bool,
+}
+
+impl FileMapping {
+    pub fn addr(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.start.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        match self.writable {
+            true => Some(unsafe { slice::from_raw_parts_mut(self.start.as_mut_ptr(), self.len) }),
+            false => None,
+        }
+    }
+
+    /// Writes every page modified since the last sync back to the file.
+    pub fn msync(&self) -> Result<(), &'static str> {
+        if !self.writable {
+            return Ok(());
+        }
+
+        for i in 0..self.pages {
+            let page = Page::<Size4KiB>::containing_address(self.start + i * PAGE_SIZE);
+            let flags = match pte_flags(page.start_address()) {
+                Some(flags) if flags.contains(PageTableFlags::DIRTY) => flags,
+                _ => continue,
+            };
+
+            // Clean the page before copying it, a write racing the copy dirties it again
+            unsafe {
+                crate::KERNEL_SPACE
+                    .wait()
+                    .lock()
+                    .update_flags(page, flags - PageTableFlags::DIRTY)
+                    .map_err(|_| "Failed to clean page")?
+                    .ignore()
+            };
+            smp::tlb_shootdown(page.start_address());
+
+            let offset = (i * PAGE_SIZE) as usize;
+            let len = (self.len - offset).min(PAGE_SIZE as usize);
+            let data = unsafe { slice::from_raw_parts(page.start_address().as_ptr::<u8>(), len) };
+            self.file
+                .write_at(self.offset + offset, data)
+                .map_err(|_| "File write failed")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        if let Err(err) = self.msync() {
+            println!("Dropping file mapping: {}", err);
+        }
+        fault::unregister(self.start);
+        vmm::unmap_range(self.start, self.pages, true);
+        vmm::release(self.start);
+    }
+}
+
+pub trait FileMmap {
+    /// Maps `len` bytes of the file from `offset` on. A writable mapping needs a writable
+    /// schema and writes dirty pages back on `msync` and when dropped.
+    fn mmap(self, offset: usize, len: usize, writable: bool) -> Result<FileMapping, &'static str>;
+}
+
+impl FileMmap for File {
+    fn mmap(self, offset: usize, len: usize, writable: bool) -> Result<FileMapping, &'static str> {
+        let size = self.size().map_err(|_| "Cannot stat file")?;
+        if len == 0 || offset.checked_add(len).map_or(true, |end| end > size) {
+            return Err("Mapping does not fit in the file");
+        }
+        if writable && !self.is_writable().unwrap_or(false) {
+            return Err("File is read only");
+        }
+
+        let pages = (len as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let start = vmm::reserve(pages, 1, "mmap")?;
+        let file = Arc::new(self);
+        let pager = FilePager {
+            file: file.clone(),
+            offset,
+            len,
+        };
+
+        let mut flags = PageTableFlags::NO_EXECUTE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        let end = start + pages * PAGE_SIZE;
+        if let Err(err) = fault::register_paged(start, end, flags, "mmap", Box::new(pager)) {
+            vmm::release(start);
+            return Err(err);
+        }
+
+        Ok(FileMapping {
+            start,
+            pages,
+            file,
+            offset,
+            len,
+            writable,
+        })
+    }
+}
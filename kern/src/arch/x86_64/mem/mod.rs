@@ -1,2 +1,61 @@
 pub mod alloc;
+pub mod dma;
+pub mod fault;
+pub mod frame;
+pub mod mmap;
 pub mod paging;
+pub mod space;
+pub mod stack;
+pub mod vmm;
+
+use crate::schema::sys::SysSchema;
+
+use ::alloc::format;
+
+pub fn register_sys(sys: &mut SysSchema) {
+    sys.insert_text("mem/frames", || {
+        // Formatting allocates, which can need a frame for the heap
+        let (usable, used, free) = {
+            let frames = crate::FRAME_ALLOC.wait().lock();
+            (
+                frames.usable_frames(),
+                frames.used_frames(),
+                frames.free_frames(),
+            )
+        };
+        format!(
+            "usable: {} KiB\nused: {} KiB\nfree: {} KiB\n",
+            usable * 4,
+            used * 4,
+            free * 4
+        )
+    });
+
+    sys.insert_text("mem/heap", || {
+        let heap = alloc::stats();
+        format!(
+            "size: {} KiB\nused: {} KiB\npeak: {} KiB\nrequested: {} KiB\nslabs: {} KiB\nslab free: {} KiB\nfragmentation: {}%\n",
+            heap.size / 1024,
+            heap.used / 1024,
+            heap.peak / 1024,
+            heap.requested / 1024,
+            heap.slab_bytes / 1024,
+            heap.slab_free_bytes / 1024,
+            heap.fragmentation()
+        )
+    });
+
+    sys.insert_text("mem/vmm", || {
+        vmm::regions()
+            .iter()
+            .map(|r| {
+                format!(
+                    "{:016x}-{:016x} {}\n",
+                    r.start,
+                    r.start + r.pages * 4096,
+                    r.name
+                )
+            })
+            .collect()
+    });
+}
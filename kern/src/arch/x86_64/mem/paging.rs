@@ -2,13 +2,11 @@ use x86_64::{
     registers::control::Cr3,
     structures::paging::{
         mapper::{MapToError, Mapper},
-        FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-
 pub unsafe fn init(phys_mem_offs: VirtAddr) -> OffsetPageTable<'static> {
     let l4_table = active_l4_table(phys_mem_offs);
     println!("Offset page table loaded");
@@ -25,32 +23,8 @@ pub unsafe fn active_l4_table(phys_mem_offs: VirtAddr) -> &'static mut PageTable
     &mut *ptable_ptr
 }
 
-pub struct BootInfoFrameAllocator {
-    mem_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(mem_map: &'static MemoryMap) -> Self {
-        Self { mem_map, next: 0 }
-    }
-
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.mem_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    *crate::PHYS_MEM_OFFSET.wait() + phys.as_u64()
 }
 
 pub fn identity_map(
@@ -59,10 +33,10 @@ pub fn identity_map(
     flags: PageTableFlags,
     inclusive: bool,
 ) -> Result<(), MapToError<Size4KiB>> {
-    use crate::{FRAME_ALLOC, MAPPER};
+    use crate::{FRAME_ALLOC, KERNEL_SPACE};
 
     let mut frame_allocator = FRAME_ALLOC.wait().lock();
-    let mut mapper = MAPPER.wait().lock();
+    let mut mapper = KERNEL_SPACE.wait().lock();
 
     if inclusive {
         for frame in PhysFrame::range_inclusive(
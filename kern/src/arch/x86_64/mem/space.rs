@@ -0,0 +1,339 @@
+use super::{frame::BitmapFrameAllocator, paging::phys_to_virt};
+use crate::arch::smp;
+
+use core::{
+    ops::{Deref, DerefMut},
+    ptr,
+};
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+    },
+    VirtAddr,
+};
+
+/// Per-space mappings live in this slice of the lower half, every other P4 entry belongs to the
+/// kernel and is shared by all address spaces.
+pub const USER_START: u64 = 0x0000_6000_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const USER_P4_FIRST: usize = (USER_START >> 39) as usize;
+const USER_P4_END: usize = (USER_END >> 39) as usize;
+const KERNEL_HIGH_P4_FIRST: usize = 256;
+
+/// Marks a page that was writable before being shared by `fork`.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
+
+fn new_table(falloc: &mut BitmapFrameAllocator) -> Result<PhysFrame, &'static str> {
+    let frame = falloc
+        .allocate_frame()
+        .ok_or("Out of memory for page tables")?;
+    table(frame).zero();
+    Ok(frame)
+}
+
+pub struct AddressSpace {
+    p4: PhysFrame,
+    mapper: OffsetPageTable<'static>,
+    kernel: bool,
+}
+
+// The page tables are only reached through the owning lock
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// Wraps the tables the bootloader left in CR3, they become the kernel's address space.
+    pub unsafe fn kernel(mapper: OffsetPageTable<'static>) -> Self {
+        Self {
+            p4: Cr3::read().0,
+            mapper,
+            kernel: true,
+        }
+    }
+
+    /// An address space with an empty user range that shares every kernel mapping.
+    pub fn new() -> Result<Self, &'static str> {
+        let kernel_p4 = crate::KERNEL_SPACE.wait().lock().p4;
+        let p4 = new_table(&mut *crate::FRAME_ALLOC.wait().lock())?;
+
+        let (src, dst) = (table(kernel_p4), table(p4));
+        for idx in (0..512).filter(|idx| !(USER_P4_FIRST..USER_P4_END).contains(idx)) {
+            dst[idx] = src[idx].clone();
+        }
+
+        Ok(Self::wrap(p4))
+    }
+
+    fn wrap(p4: PhysFrame) -> Self {
+        let offset = *crate::PHYS_MEM_OFFSET.wait();
+        Self {
+            p4,
+            mapper: unsafe { OffsetPageTable::new(table(p4), offset) },
+            kernel: false,
+        }
+    }
+
+    pub fn p4(&self) -> PhysFrame {
+        self.p4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4
+    }
+
+    /// Switches the calling CPU to this address space.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.p4, Cr3Flags::empty());
+    }
+
+    /// Duplicates the user range. Writable pages end up shared read-only by both spaces and
+    /// are copied by whichever one writes to them first.
+    pub fn fork(&mut self) -> Result<Self, &'static str> {
+        // Checked up front so a refusal leaves the parent untouched
+        let src = table(self.p4);
+        let huge = (USER_P4_FIRST..USER_P4_END)
+            .filter(|idx| src[*idx].flags().contains(PageTableFlags::PRESENT))
+            .any(|idx| has_huge_pages(src[idx].frame().unwrap(), 3));
+        if huge {
+            return Err("Huge pages cannot be forked");
+        }
+
+        let child = Self::new()?;
+
+        // Holding the allocator keeps fault resolution from racing the walk
+        let mut falloc = crate::FRAME_ALLOC.wait().lock();
+        let dst = table(child.p4);
+        let mut result = Ok(());
+        for idx in USER_P4_FIRST..USER_P4_END {
+            if !src[idx].flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            match fork_table(&mut falloc, src[idx].frame().unwrap(), 3) {
+                Ok(copy) => dst[idx].set_frame(copy, src[idx].flags()),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        drop(falloc);
+
+        if let Err(e) = result {
+            // The child gives back its tables and shares, then pages nobody else holds become
+            // writable again
+            drop(child);
+            let mut falloc = crate::FRAME_ALLOC.wait().lock();
+            for idx in USER_P4_FIRST..USER_P4_END {
+                if src[idx].flags().contains(PageTableFlags::PRESENT) {
+                    restore_writable(&mut falloc, src[idx].frame().unwrap(), 3);
+                }
+            }
+            drop(falloc);
+            // Other CPUs may be running this space too
+            smp::tlb_shootdown_all();
+            return Err(e);
+        }
+
+        smp::tlb_shootdown_all();
+        Ok(child)
+    }
+}
+
+fn has_huge_pages(frame: PhysFrame, level: usize) -> bool {
+    level > 1
+        && table(frame).iter().any(|entry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT)
+                && (flags.contains(PageTableFlags::HUGE_PAGE)
+                    || has_huge_pages(entry.frame().unwrap(), level - 1))
+        })
+}
+
+/// Copies the table and everything below it, freeing the partial copy if it runs out of memory.
+fn fork_table(
+    falloc: &mut BitmapFrameAllocator,
+    src: PhysFrame,
+    level: usize,
+) -> Result<PhysFrame, &'static str> {
+    let copy = new_table(falloc)?;
+    let (src, dst) = (table(src), table(copy));
+
+    for (from, to) in src.iter_mut().zip(dst.iter_mut()) {
+        let flags = from.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let frame = from.frame().unwrap();
+        if level > 1 {
+            match fork_table(falloc, frame, level - 1) {
+                Ok(child) => to.set_frame(child, flags),
+                Err(e) => {
+                    free_table(falloc, copy, level);
+                    return Err(e);
+                }
+            }
+            continue;
+        }
+
+        let flags = match flags.contains(PageTableFlags::WRITABLE) {
+            true => (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE,
+            false => flags,
+        };
+        from.set_frame(frame, flags);
+        to.set_frame(frame, flags);
+        falloc.share(frame);
+    }
+
+    Ok(copy)
+}
+
+fn restore_writable(falloc: &mut BitmapFrameAllocator, frame: PhysFrame, level: usize) {
+    for entry in table(frame).iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let child = entry.frame().unwrap();
+        if level > 1 {
+            restore_writable(falloc, child, level - 1);
+        } else if flags.contains(COPY_ON_WRITE) && falloc.ref_count(child) == 1 {
+            entry.set_frame(child, (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+fn free_table(falloc: &mut BitmapFrameAllocator, frame: PhysFrame, level: usize) {
+    for entry in table(frame).iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let child = entry.frame().unwrap();
+        if level > 1 {
+            free_table(falloc, child, level - 1);
+        } else {
+            unsafe { falloc.deallocate_frame(child) };
+        }
+    }
+    unsafe { falloc.deallocate_frame(frame) };
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.kernel, "The kernel address space cannot be dropped");
+        assert!(!self.is_active(), "Dropping the active address space");
+
+        let mut falloc = crate::FRAME_ALLOC.wait().lock();
+        let p4 = table(self.p4);
+        for idx in USER_P4_FIRST..USER_P4_END {
+            if p4[idx].flags().contains(PageTableFlags::PRESENT) {
+                free_table(&mut falloc, p4[idx].frame().unwrap(), 3);
+            }
+        }
+        unsafe { falloc.deallocate_frame(self.p4) };
+    }
+}
+
+impl Deref for AddressSpace {
+    type Target = OffsetPageTable<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.mapper
+    }
+}
+
+impl DerefMut for AddressSpace {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.mapper
+    }
+}
+
+/// Gives the whole kernel range its upper level tables up front, so kernel mappings made later
+/// show up in every address space.
+pub fn init() {
+    let kernel_p4 = crate::KERNEL_SPACE.wait().lock().p4;
+    let p4 = table(kernel_p4);
+
+    for idx in USER_P4_FIRST..USER_P4_END {
+        assert!(
+            p4[idx].is_unused(),
+            "Kernel mappings overlap the user address range"
+        );
+    }
+
+    let mut falloc = crate::FRAME_ALLOC.wait().lock();
+    for idx in KERNEL_HIGH_P4_FIRST..512 {
+        if p4[idx].is_unused() {
+            let frame = new_table(&mut falloc).expect("Out of memory for kernel page tables");
+            p4[idx].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+/// Resolves a write to a copy-on-write page in the active address space.
+pub fn resolve_cow(addr: VirtAddr) -> bool {
+    if addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+        return false;
+    }
+
+    let mut falloc = crate::FRAME_ALLOC.wait().lock();
+    let mut frame = Cr3::read().0;
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, idx) in indices.iter().enumerate() {
+        let entry = &mut table(frame)[*idx];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level < 3 {
+            frame = entry.frame().unwrap();
+            continue;
+        }
+        // Another CPU sharing this space may have copied the page already
+        if flags.contains(PageTableFlags::WRITABLE) {
+            tlb::flush(addr);
+            return true;
+        }
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+
+        let old = entry.frame().unwrap();
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        // The last owner keeps the frame, everyone else gets a private copy
+        if falloc.ref_count(old) > 1 {
+            let new = match falloc.allocate_frame() {
+                Some(new) => new,
+                None => return false,
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    phys_to_virt(old.start_address()).as_ptr::<u8>(),
+                    phys_to_virt(new.start_address()).as_mut_ptr::<u8>(),
+                    4096,
+                );
+                falloc.deallocate_frame(old);
+            }
+            entry.set_frame(new, flags);
+        } else {
+            entry.set_frame(old, flags);
+        }
+        // Stale read-only entries elsewhere would keep reading the old frame
+        drop(falloc);
+        smp::fault_tlb_shootdown(addr);
+        return true;
+    }
+
+    false
+}
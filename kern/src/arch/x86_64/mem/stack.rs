@@ -0,0 +1,56 @@
+use super::{fault, vmm};
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+// A CPU switching to a fresh stack may not have a fault handler yet, so the top is backed up front
+const EAGER_PAGES: u64 = 2;
+
+/// A kernel stack backed on demand, with an unmapped guard page under it.
+pub struct KernelStack {
+    base: VirtAddr,
+    pages: u64,
+}
+
+impl KernelStack {
+    pub fn new(pages: u64, name: &'static str) -> Result<Self, &'static str> {
+        let pages = pages.max(EAGER_PAGES);
+        let base = vmm::reserve(pages + 1, 1, name)?;
+        let bottom = base + PAGE_SIZE;
+        let top = bottom + pages * PAGE_SIZE;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        fault::register_guard(base, bottom, name)?;
+        if let Err(err) = fault::register_lazy(bottom, top, flags, name) {
+            fault::unregister(base);
+            vmm::release(base);
+            return Err(err);
+        }
+
+        let stack = Self { base, pages };
+        let eager = top - EAGER_PAGES * PAGE_SIZE;
+        for page in 0..EAGER_PAGES {
+            unsafe {
+                (eager + page * PAGE_SIZE)
+                    .as_mut_ptr::<u8>()
+                    .write_volatile(0)
+            };
+        }
+        Ok(stack)
+    }
+
+    /// The initial stack pointer, 16-byte aligned.
+    pub fn top(&self) -> VirtAddr {
+        self.base + (self.pages + 1) * PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        fault::unregister(self.base);
+        fault::unregister(self.base + PAGE_SIZE);
+        vmm::unmap_range(self.base + PAGE_SIZE, self.pages, true);
+        vmm::release(self.base);
+    }
+}
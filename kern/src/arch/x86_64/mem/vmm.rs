@@ -0,0 +1,288 @@
+use crate::arch::smp;
+
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Dynamic kernel mappings live in their own slice of the higher half, away from the heap
+/// and from the bootloader's physical memory window.
+pub const VMM_START: u64 = 0xFFFF_A000_0000_0000;
+pub const VMM_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+const IA32_PAT: u32 = 0x277;
+// WB, WT, UC-, UC, then WC in slot 4 where the power-on value repeats WB
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+// On a 4 KiB entry bit 7 selects the upper half of the PAT
+const PTE_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    Uncached,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PTE_PAT,
+            CacheMode::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: u64,
+    pub pages: u64,
+    pub name: &'static str,
+}
+
+struct Regions {
+    free: Vec<(u64, u64)>,
+    used: Vec<Region>,
+}
+
+static REGIONS: Mutex<Option<Regions>> = Mutex::new(None);
+
+/// Programs the PAT of the calling CPU, every CPU must agree on it.
+pub fn init_pat() {
+    unsafe { Msr::new(IA32_PAT).write(PAT_VALUE) };
+}
+
+pub fn init() {
+    init_pat();
+    *REGIONS.lock() = Some(Regions {
+        free: vec![(VMM_START, VMM_SIZE / PAGE_SIZE)],
+        used: Vec::new(),
+    });
+}
+
+/// Reserves `pages` pages of address space without backing them, aligned to `align` pages.
+pub fn reserve(pages: u64, align: u64, name: &'static str) -> Result<VirtAddr, &'static str> {
+    let align = align.max(1) * PAGE_SIZE;
+    let mut regions = REGIONS.lock();
+    let regions = regions.as_mut().ok_or("VMM not initialized")?;
+
+    for idx in 0..regions.free.len() {
+        let (start, count) = regions.free[idx];
+        let aligned = (start + align - 1) / align * align;
+        let skip = (aligned - start) / PAGE_SIZE;
+        if skip + pages > count {
+            continue;
+        }
+
+        let tail = count - skip - pages;
+        match (skip, tail) {
+            (0, 0) => {
+                regions.free.remove(idx);
+            }
+            (0, _) => regions.free[idx] = (aligned + pages * PAGE_SIZE, tail),
+            (_, 0) => regions.free[idx] = (start, skip),
+            _ => {
+                regions.free[idx] = (start, skip);
+                regions
+                    .free
+                    .insert(idx + 1, (aligned + pages * PAGE_SIZE, tail));
+            }
+        }
+
+        regions.used.push(Region {
+            start: aligned,
+            pages,
+            name,
+        });
+        return Ok(VirtAddr::new(aligned));
+    }
+
+    Err("Kernel address space exhausted")
+}
+
+/// Returns a range handed out by `reserve`. Anything still mapped in it must be unmapped first.
+pub fn release(start: VirtAddr) {
+    let start = start.as_u64();
+    let mut regions = REGIONS.lock();
+    let regions = match regions.as_mut() {
+        Some(regions) => regions,
+        None => return,
+    };
+
+    let pages = match regions.used.iter().position(|r| r.start == start) {
+        Some(idx) => regions.used.swap_remove(idx).pages,
+        None => return,
+    };
+
+    let idx = regions.free.iter().position(|(s, _)| *s > start);
+    let idx = idx.unwrap_or_else(|| regions.free.len());
+    regions.free.insert(idx, (start, pages));
+
+    // Merge with the neighbours so the free list stays short
+    if idx + 1 < regions.free.len() {
+        let (next, next_pages) = regions.free[idx + 1];
+        if start + pages * PAGE_SIZE == next {
+            regions.free[idx].1 += next_pages;
+            regions.free.remove(idx + 1);
+        }
+    }
+    if idx > 0 {
+        let (prev, prev_pages) = regions.free[idx - 1];
+        if prev + prev_pages * PAGE_SIZE == start {
+            regions.free[idx - 1].1 += regions.free[idx].1;
+            regions.free.remove(idx);
+        }
+    }
+}
+
+pub fn regions() -> Vec<Region> {
+    REGIONS
+        .lock()
+        .as_ref()
+        .map(|regions| regions.used.clone())
+        .unwrap_or_default()
+}
+
+fn map_page(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+    use crate::{FRAME_ALLOC, KERNEL_SPACE};
+
+    let mut falloc = FRAME_ALLOC.wait().lock();
+    let mut mapper = KERNEL_SPACE.wait().lock();
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut *falloc)
+            .map_err(|_| "Failed to map page")?
+            .flush()
+    };
+    Ok(())
+}
+
+fn unmap_page(page: Page) -> Option<PhysFrame> {
+    let frame = {
+        let mut mapper = crate::KERNEL_SPACE.wait().lock();
+        let (frame, flush) = mapper.unmap(page).ok()?;
+        flush.ignore();
+        frame
+    };
+    smp::tlb_shootdown(page.start_address());
+    Some(frame)
+}
+
+/// A range of kernel address space that is unmapped and released when dropped.
+pub struct Mapping {
+    start: VirtAddr,
+    offset: u64,
+    pages: u64,
+    owns_frames: bool,
+}
+
+impl Mapping {
+    /// The virtual address of the first byte that was asked for.
+    pub fn addr(&self) -> VirtAddr {
+        self.start + self.offset
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.addr().as_mut_ptr()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE - self.offset
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unmap_range(self.start, self.pages, self.owns_frames);
+        release(self.start);
+    }
+}
+
+/// Unmaps whatever is mapped in `pages` pages from `start`, optionally freeing the frames behind it.
+pub fn unmap_range(start: VirtAddr, pages: u64, free_frames: bool) {
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+        if let Some(frame) = unmap_page(page) {
+            if free_frames {
+                unsafe { crate::FRAME_ALLOC.wait().lock().deallocate_frame(frame) };
+            }
+        }
+    }
+}
+
+/// Maps `size` bytes of physical memory, typically device registers or a framebuffer.
+pub fn map_phys(
+    phys: PhysAddr,
+    size: u64,
+    cache: CacheMode,
+    name: &'static str,
+) -> Result<Mapping, &'static str> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let pages = (offset + size.max(1) + PAGE_SIZE - 1) / PAGE_SIZE;
+    let start = reserve(pages, 1, name)?;
+
+    // From here on the mapping cleans up after itself
+    let mut mapping = Mapping {
+        start,
+        offset,
+        pages: 0,
+        owns_frames: false,
+    };
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+    for i in 0..pages {
+        map_page(
+            Page::containing_address(start + i * PAGE_SIZE),
+            first + i,
+            flags,
+        )?;
+        mapping.pages += 1;
+    }
+
+    Ok(mapping)
+}
+
+/// Maps `pages` pages of fresh, zeroed memory.
+pub fn map_anon(
+    pages: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<Mapping, &'static str> {
+    let start = reserve(pages, 1, name)?;
+    let mut mapping = Mapping {
+        start,
+        offset: 0,
+        pages: 0,
+        owns_frames: true,
+    };
+
+    for i in 0..pages {
+        let frame = crate::FRAME_ALLOC
+            .wait()
+            .lock()
+            .allocate_frame()
+            .ok_or("Out of physical memory")?;
+        let page = Page::containing_address(start + i * PAGE_SIZE);
+        if let Err(err) = map_page(page, frame, flags | PageTableFlags::PRESENT) {
+            unsafe { crate::FRAME_ALLOC.wait().lock().deallocate_frame(frame) };
+            return Err(err);
+        }
+        mapping.pages += 1;
+        unsafe { core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, 4096) };
+    }
+
+    Ok(mapping)
+}
@@ -1,11 +1,27 @@
 #[macro_use]
 pub mod print;
+pub mod acpi;
+pub mod backtrace;
+pub mod clock;
+pub mod exception;
+pub mod fpu;
+pub mod gdb;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
+pub mod lapic;
 pub mod mem;
+pub mod net;
 pub mod pci;
 pub mod pic;
+pub mod pit;
+pub mod power;
+pub mod rtc;
+pub mod smp;
 pub mod task;
+pub mod tsc;
 pub mod vga_text;
 pub mod video;
 
@@ -66,6 +82,16 @@ impl CharDevice for SerialDevice {
 }
 
 pub fn _print(args: fmt::Arguments) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        _console_print(args);
+        crate::log::record(args);
+    });
+}
+
+#[doc(hidden)]
+pub fn _console_print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
@@ -75,11 +101,51 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Skips the VGA console while its lock is taken and writes the serial port directly instead of
+/// waiting for `SERIAL1`.
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writer.write_fmt(args);
+        }
+        let _ = match SERIAL1.try_lock() {
+            Some(mut serial) => serial.write_fmt(args),
+            None => unsafe { SerialPort::new(0x3F8) }.write_fmt(args),
+        };
+    });
+}
+
 pub fn init() {
-    gdt::init();
-    task::mouse::init();
-    idt::init();
-    pic::init();
+    clock::step("gdt", gdt::init);
+    clock::step("idt", idt::init);
+    clock::step("fpu", fpu::init);
+    clock::step("acpi", acpi::init);
+    clock::step("clock", clock::init);
+    clock::step("irq", irq::init);
+    clock::step("pit", pit::init);
+    clock::step("keyboard", task::keyboard::init);
+    clock::step("mouse", task::mouse::init);
+    clock::step("serial", task::serial::init);
+    clock::step("gdb", gdb::init);
+    clock::step("pci", || {
+        pci::init();
+        pci::driver::register(net::e1000::E1000Driver::new());
+        pci::driver::register(video::bochs::BochsDriver);
+        pci::driver::bind_all();
+    });
+    clock::step("smp", smp::init);
+
+    let total: u64 = clock::boot_steps().iter().map(|(_, nanos)| nanos).sum();
+    info!(
+        target: "clock",
+        "Machine brought up in {}.{:03} ms",
+        total / 1_000_000,
+        total / 1000 % 1000
+    );
 
     x86_64::instructions::interrupts::enable();
 }
@@ -0,0 +1,499 @@
+use super::INTERFACES;
+use crate::arch::{
+    idt::HandlerId,
+    irq::{self, Polarity, Trigger},
+    mem::{
+        dma::DmaBuffer,
+        vmm::{CacheMode, Mapping},
+    },
+    pci::{driver::PCIDriver, PCIDevice, PCIDeviceAddress, PCIFind, PCIBAR},
+};
+
+use alloc::vec::Vec;
+use core::{ptr, slice};
+use lazy_static::lazy_static;
+use lib_kern::net::{LinkStatus, MacAddress, NetError, NetworkDevice, ETH_FRAME_MAX};
+use spinning::Mutex;
+use x86_64::PhysAddr;
+
+lazy_static! {
+    pub static ref E1000_SIGNATURE: PCIFind = PCIFind::new(0x8086, 0x100E);
+}
+
+#[allow(unused)]
+mod registers {
+    pub const CTRL: u32 = 0x0000;
+    pub const STATUS: u32 = 0x0008;
+    pub const EERD: u32 = 0x0014;
+    pub const ICR: u32 = 0x00C0;
+    pub const IMS: u32 = 0x00D0;
+    pub const IMC: u32 = 0x00D8;
+    pub const RCTL: u32 = 0x0100;
+    pub const TCTL: u32 = 0x0400;
+    pub const TIPG: u32 = 0x0410;
+    pub const RDBAL: u32 = 0x2800;
+    pub const RDBAH: u32 = 0x2804;
+    pub const RDLEN: u32 = 0x2808;
+    pub const RDH: u32 = 0x2810;
+    pub const RDT: u32 = 0x2818;
+    pub const TDBAL: u32 = 0x3800;
+    pub const TDBAH: u32 = 0x3804;
+    pub const TDLEN: u32 = 0x3808;
+    pub const TDH: u32 = 0x3810;
+    pub const TDT: u32 = 0x3818;
+    pub const MTA: u32 = 0x5200;
+    pub const RAL0: u32 = 0x5400;
+    pub const RAH0: u32 = 0x5404;
+
+    pub const CTRL_ASDE: u32 = 1 << 5;
+    pub const CTRL_SLU: u32 = 1 << 6;
+    pub const CTRL_RST: u32 = 1 << 26;
+
+    pub const STATUS_LU: u32 = 1 << 1;
+
+    pub const EERD_START: u32 = 1 << 0;
+    pub const EERD_DONE: u32 = 1 << 4;
+
+    pub const ICR_TXDW: u32 = 1 << 0;
+    pub const ICR_LSC: u32 = 1 << 2;
+    pub const ICR_RXDMT0: u32 = 1 << 4;
+    pub const ICR_RXO: u32 = 1 << 6;
+    pub const ICR_RXT0: u32 = 1 << 7;
+
+    pub const RCTL_EN: u32 = 1 << 1;
+    pub const RCTL_BAM: u32 = 1 << 15;
+    pub const RCTL_BSIZE_2048: u32 = 0 << 16;
+    pub const RCTL_SECRC: u32 = 1 << 26;
+
+    pub const TCTL_EN: u32 = 1 << 1;
+    pub const TCTL_PSP: u32 = 1 << 3;
+    pub const TCTL_CT: u32 = 0x10 << 4;
+    pub const TCTL_COLD: u32 = 0x40 << 12;
+
+    pub const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+    pub const RAH_AV: u32 = 1 << 31;
+
+    pub const DESC_DD: u8 = 1 << 0;
+    pub const DESC_EOP: u8 = 1 << 1;
+
+    pub const CMD_EOP: u8 = 1 << 0;
+    pub const CMD_IFCS: u8 = 1 << 1;
+    pub const CMD_RS: u8 = 1 << 3;
+}
+
+const RX_RING_SIZE: usize = 32;
+const TX_RING_SIZE: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RxDesc {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TxDesc {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+fn handle_interrupt(mmio: u64) {
+    let cause = unsafe { ptr::read_volatile((mmio + registers::ICR as u64) as *const u32) };
+    if cause & registers::ICR_LSC != 0 {
+        let status = unsafe { ptr::read_volatile((mmio + registers::STATUS as u64) as *const u32) };
+        if status & registers::STATUS_LU != 0 {
+            info!("0x{:016x}: link up", mmio);
+        } else {
+            warn!("0x{:016x}: link down", mmio);
+        }
+    }
+}
+
+pub struct E1000Driver {
+    ifaces: Mutex<Vec<(PCIDeviceAddress, &'static str)>>,
+}
+
+impl E1000Driver {
+    pub fn new() -> Self {
+        Self {
+            ifaces: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl PCIDriver for E1000Driver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn patterns(&self) -> &[PCIFind] {
+        slice::from_ref(&*E1000_SIGNATURE)
+    }
+
+    fn probe(&self, dev: &PCIDevice) -> Result<(), &'static str> {
+        let nic = E1000::new(dev)?.init();
+        info!("0x{:08x}: MAC {}", nic.addr(), nic.mac_address());
+        info!("0x{:08x}: link {:?}", nic.addr(), nic.link_status());
+
+        let name = super::alloc_iface_name("eth");
+        INTERFACES
+            .lock()
+            .insert(name, nic)
+            .or(Err("Interface name already registered"))?;
+        self.ifaces.lock().push((dev.address, name));
+        Ok(())
+    }
+
+    fn remove(&self, dev: &PCIDevice) {
+        let mut ifaces = self.ifaces.lock();
+        if let Some(pos) = ifaces.iter().position(|(addr, _)| *addr == dev.address) {
+            let (_, name) = ifaces.remove(pos);
+            INTERFACES.lock().remove(name).ok();
+        }
+    }
+}
+
+#[allow(unused)]
+pub struct E1000 {
+    pci_device: PCIDevice,
+    mmio_bar: PCIBAR,
+    mmio_map: Mapping,
+    mmio: u64,
+    mac: MacAddress,
+    msi_vector: Option<u8>,
+    irq_handler: Option<(u8, HandlerId)>,
+    rx_ring: DmaBuffer<[RxDesc; RX_RING_SIZE]>,
+    rx_buffers: DmaBuffer<[u8]>,
+    rx_next: usize,
+    tx_ring: DmaBuffer<[TxDesc; TX_RING_SIZE]>,
+    tx_buffers: DmaBuffer<[u8]>,
+    tx_next: usize,
+}
+
+impl NetworkDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        if self.read_reg(registers::STATUS) & registers::STATUS_LU != 0 {
+            LinkStatus::Up
+        } else {
+            LinkStatus::Down
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > ETH_FRAME_MAX {
+            return Err(NetError::FrameTooLarge);
+        }
+        if self.link_status() == LinkStatus::Down {
+            return Err(NetError::LinkDown);
+        }
+
+        let idx = self.tx_next;
+        let desc = self.tx_desc(idx);
+        if desc.status & registers::DESC_DD == 0 {
+            return Err(NetError::QueueFull);
+        }
+
+        let offset = idx * BUFFER_SIZE;
+        self.tx_buffers[offset..offset + frame.len()].copy_from_slice(frame);
+        self.write_tx_desc(
+            idx,
+            TxDesc {
+                addr: self.tx_buffer_addr(idx).as_u64(),
+                length: frame.len() as u16,
+                cmd: registers::CMD_EOP | registers::CMD_IFCS | registers::CMD_RS,
+                ..TxDesc::default()
+            },
+        );
+
+        self.tx_next = (idx + 1) % TX_RING_SIZE;
+        self.write_reg(registers::TDT, self.tx_next as u32);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let idx = self.rx_next;
+            let desc = self.rx_desc(idx);
+            if desc.status & registers::DESC_DD == 0 {
+                return None;
+            }
+
+            // Broken frames are recycled without being handed out
+            let frame = match desc.status & registers::DESC_EOP != 0 && desc.errors == 0 {
+                true => {
+                    let offset = idx * BUFFER_SIZE;
+                    Some(self.rx_buffers[offset..offset + desc.length as usize].to_vec())
+                }
+                false => None,
+            };
+
+            self.write_rx_desc(
+                idx,
+                RxDesc {
+                    addr: self.rx_buffer_addr(idx).as_u64(),
+                    ..RxDesc::default()
+                },
+            );
+            self.write_reg(registers::RDT, idx as u32);
+            self.rx_next = (idx + 1) % RX_RING_SIZE;
+
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+impl E1000 {
+    pub fn new(dev: &PCIDevice) -> Result<Self, &'static str> {
+        let mmio_bar = *dev.bar(0).ok_or("E1000 has no MMIO BAR")?;
+        let mmio_map = mmio_bar.map(CacheMode::Uncached)?;
+        let mmio = mmio_map.addr().as_u64();
+        dev.enable_bus_mastering();
+
+        Ok(Self {
+            pci_device: *dev,
+            mmio_bar,
+            mmio_map,
+            mmio,
+            mac: MacAddress([0; 6]),
+            msi_vector: None,
+            irq_handler: None,
+            rx_ring: DmaBuffer::new([RxDesc::default(); RX_RING_SIZE])?,
+            rx_buffers: DmaBuffer::new_slice(RX_RING_SIZE * BUFFER_SIZE, 0)?,
+            rx_next: 0,
+            tx_ring: DmaBuffer::new([TxDesc::default(); TX_RING_SIZE])?,
+            tx_buffers: DmaBuffer::new_slice(TX_RING_SIZE * BUFFER_SIZE, 0)?,
+            tx_next: 0,
+        })
+    }
+
+    pub fn addr(&self) -> u32 {
+        u32::from(self.pci_device.address)
+    }
+
+    pub fn init(mut self) -> Self {
+        self.reset();
+        self.mac = self.read_mac();
+        self.init_rx();
+        self.init_tx();
+
+        // Each NIC's handler reads its own registers
+        let mmio = self.mmio;
+        match self
+            .pci_device
+            .enable_msi("e1000", move || handle_interrupt(mmio))
+        {
+            Ok(vector) => self.msi_vector = Some(vector),
+            Err(_) => {
+                // INTx is a shared, level triggered and active low line
+                let irq = self.pci_device.interrupt_line();
+                match irq::register_with(
+                    irq,
+                    "e1000",
+                    Trigger::Level,
+                    Polarity::ActiveLow,
+                    move || handle_interrupt(mmio),
+                ) {
+                    Ok(id) => self.irq_handler = Some((irq, id)),
+                    Err(e) => error!("0x{:08x}: no interrupt on IRQ {}: {}", self.addr(), irq, e),
+                }
+            }
+        }
+
+        // Received frames are polled for, only link changes interrupt
+        self.write_reg(registers::IMS, registers::ICR_LSC);
+        self.read_reg(registers::ICR);
+
+        self
+    }
+
+    fn reset(&mut self) {
+        self.write_reg(registers::IMC, 0xFFFFFFFF);
+        self.write_reg(
+            registers::CTRL,
+            self.read_reg(registers::CTRL) | registers::CTRL_RST,
+        );
+        while self.read_reg(registers::CTRL) & registers::CTRL_RST != 0 {}
+
+        self.write_reg(registers::IMC, 0xFFFFFFFF);
+        self.read_reg(registers::ICR);
+
+        self.write_reg(
+            registers::CTRL,
+            self.read_reg(registers::CTRL) | registers::CTRL_SLU | registers::CTRL_ASDE,
+        );
+    }
+
+    fn read_mac(&mut self) -> MacAddress {
+        let mut mac = [0u8; 6];
+
+        match self.read_eeprom(0) {
+            Some(word0) => {
+                let word1 = self.read_eeprom(1).unwrap_or(0);
+                let word2 = self.read_eeprom(2).unwrap_or(0);
+                for (i, word) in [word0, word1, word2].iter().enumerate() {
+                    mac[i * 2] = (*word & 0xFF) as u8;
+                    mac[i * 2 + 1] = (*word >> 8) as u8;
+                }
+            }
+            None => {
+                let lo = self.read_reg(registers::RAL0);
+                let hi = self.read_reg(registers::RAH0);
+                mac[..4].copy_from_slice(&lo.to_le_bytes());
+                mac[4..].copy_from_slice(&hi.to_le_bytes()[..2]);
+            }
+        }
+
+        self.write_reg(
+            registers::RAL0,
+            u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+        );
+        self.write_reg(
+            registers::RAH0,
+            u32::from_le_bytes([mac[4], mac[5], 0, 0]) | registers::RAH_AV,
+        );
+
+        MacAddress(mac)
+    }
+
+    fn read_eeprom(&mut self, word: u8) -> Option<u16> {
+        self.write_reg(registers::EERD, registers::EERD_START | (word as u32) << 8);
+
+        for _ in 0..10000 {
+            let val = self.read_reg(registers::EERD);
+            if val & registers::EERD_DONE != 0 {
+                return Some((val >> 16) as u16);
+            }
+        }
+        None
+    }
+
+    fn init_rx(&mut self) {
+        for i in 0..RX_RING_SIZE {
+            self.write_rx_desc(
+                i,
+                RxDesc {
+                    addr: self.rx_buffer_addr(i).as_u64(),
+                    ..RxDesc::default()
+                },
+            );
+        }
+
+        for i in 0..128 {
+            self.write_reg(registers::MTA + i * 4, 0);
+        }
+
+        self.write_reg(registers::RDBAL, self.rx_ring.phys().as_u64() as u32);
+        self.write_reg(
+            registers::RDBAH,
+            (self.rx_ring.phys().as_u64() >> 32) as u32,
+        );
+        self.write_reg(
+            registers::RDLEN,
+            (RX_RING_SIZE * core::mem::size_of::<RxDesc>()) as u32,
+        );
+        self.write_reg(registers::RDH, 0);
+        self.write_reg(registers::RDT, (RX_RING_SIZE - 1) as u32);
+        self.write_reg(
+            registers::RCTL,
+            registers::RCTL_EN
+                | registers::RCTL_BAM
+                | registers::RCTL_BSIZE_2048
+                | registers::RCTL_SECRC,
+        );
+    }
+
+    fn init_tx(&mut self) {
+        for i in 0..TX_RING_SIZE {
+            self.write_tx_desc(
+                i,
+                TxDesc {
+                    addr: self.tx_buffer_addr(i).as_u64(),
+                    status: registers::DESC_DD,
+                    ..TxDesc::default()
+                },
+            );
+        }
+
+        self.write_reg(registers::TDBAL, self.tx_ring.phys().as_u64() as u32);
+        self.write_reg(
+            registers::TDBAH,
+            (self.tx_ring.phys().as_u64() >> 32) as u32,
+        );
+        self.write_reg(
+            registers::TDLEN,
+            (TX_RING_SIZE * core::mem::size_of::<TxDesc>()) as u32,
+        );
+        self.write_reg(registers::TDH, 0);
+        self.write_reg(registers::TDT, 0);
+        self.write_reg(
+            registers::TCTL,
+            registers::TCTL_EN | registers::TCTL_PSP | registers::TCTL_CT | registers::TCTL_COLD,
+        );
+        self.write_reg(registers::TIPG, registers::TIPG_DEFAULT);
+    }
+
+    fn rx_buffer_addr(&self, idx: usize) -> PhysAddr {
+        self.rx_buffers.phys() + idx * BUFFER_SIZE
+    }
+
+    fn tx_buffer_addr(&self, idx: usize) -> PhysAddr {
+        self.tx_buffers.phys() + idx * BUFFER_SIZE
+    }
+
+    fn rx_desc(&self, idx: usize) -> RxDesc {
+        unsafe { ptr::read_volatile(&self.rx_ring[idx]) }
+    }
+
+    fn write_rx_desc(&mut self, idx: usize, desc: RxDesc) {
+        unsafe { ptr::write_volatile(&mut self.rx_ring[idx], desc) }
+    }
+
+    fn tx_desc(&self, idx: usize) -> TxDesc {
+        unsafe { ptr::read_volatile(&self.tx_ring[idx]) }
+    }
+
+    fn write_tx_desc(&mut self, idx: usize, desc: TxDesc) {
+        unsafe { ptr::write_volatile(&mut self.tx_ring[idx], desc) }
+    }
+
+    fn read_reg(&self, reg: u32) -> u32 {
+        unsafe { ptr::read_volatile((self.mmio + reg as u64) as *const u32) }
+    }
+
+    fn write_reg(&mut self, reg: u32, val: u32) {
+        unsafe { ptr::write_volatile((self.mmio + reg as u64) as *mut u32, val) }
+    }
+}
+
+impl Drop for E1000 {
+    fn drop(&mut self) {
+        self.write_reg(registers::IMC, 0xFFFFFFFF);
+        self.write_reg(registers::RCTL, 0);
+        self.write_reg(registers::TCTL, 0);
+        if let Some(vector) = self.msi_vector.take() {
+            self.pci_device.disable_msi(vector);
+        }
+        if let Some((irq, id)) = self.irq_handler.take() {
+            irq::unregister(irq, id);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+pub mod e1000;
+
+use alloc::{boxed::Box, format};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use lib_kern::net::InterfaceMap;
+use spinning::Mutex;
+
+lazy_static! {
+    pub static ref INTERFACES: Mutex<InterfaceMap> = Mutex::new(InterfaceMap::new());
+}
+
+fn alloc_iface_name(prefix: &str) -> &'static str {
+    static NEXT_IFACE: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("{}{}", prefix, NEXT_IFACE.fetch_add(1, Ordering::Relaxed));
+    Box::leak(name.into_boxed_str())
+}
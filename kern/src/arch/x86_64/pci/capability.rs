@@ -0,0 +1,71 @@
+use super::PCIDevice;
+
+use alloc::vec::Vec;
+
+const PCIFIELD_STATUS: u16 = 0x06;
+const PCIFIELD_CAPABILITIES_POINTER: u16 = 0x34;
+
+const PCISTATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+pub const PCICAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const PCICAP_MSI: u8 = 0x05;
+pub const PCICAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const PCICAP_PCIE: u8 = 0x10;
+pub const PCICAP_MSIX: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PCICapabilityKind {
+    PowerManagement,
+    Msi,
+    MsiX,
+    PciExpress,
+    VendorSpecific,
+    Other(u8),
+}
+
+impl From<u8> for PCICapabilityKind {
+    fn from(id: u8) -> Self {
+        match id {
+            PCICAP_POWER_MANAGEMENT => Self::PowerManagement,
+            PCICAP_MSI => Self::Msi,
+            PCICAP_MSIX => Self::MsiX,
+            PCICAP_PCIE => Self::PciExpress,
+            PCICAP_VENDOR_SPECIFIC => Self::VendorSpecific,
+            id => Self::Other(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PCICapability {
+    pub kind: PCICapabilityKind,
+    pub offset: u16,
+}
+
+impl PCIDevice {
+    pub fn capabilities(&self) -> Vec<PCICapability> {
+        let mut caps = Vec::new();
+        if self.read16(PCIFIELD_STATUS) & PCISTATUS_CAPABILITIES_LIST == 0 {
+            return caps;
+        }
+
+        let mut offset = (self.read8(PCIFIELD_CAPABILITIES_POINTER) & !0x3) as u16;
+        // A malformed list could loop forever, there is only room for 48 capabilities
+        while offset != 0 && caps.len() < 48 {
+            caps.push(PCICapability {
+                kind: PCICapabilityKind::from(self.read8(offset)),
+                offset,
+            });
+            offset = (self.read8(offset + 1) & !0x3) as u16;
+        }
+
+        caps
+    }
+
+    pub fn find_capability(&self, kind: PCICapabilityKind) -> Option<u16> {
+        self.capabilities()
+            .iter()
+            .find(|cap| cap.kind == kind)
+            .map(|cap| cap.offset)
+    }
+}
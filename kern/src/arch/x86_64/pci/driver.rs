@@ -0,0 +1,99 @@
+use super::{devices, PCIDevice, PCIDeviceAddress, PCIFind};
+
+use alloc::{boxed::Box, vec::Vec};
+use lazy_static::lazy_static;
+use spinning::Mutex;
+
+pub trait PCIDriver {
+    fn name(&self) -> &'static str;
+    fn patterns(&self) -> &[PCIFind];
+
+    fn probe(&self, dev: &PCIDevice) -> Result<(), &'static str>;
+    fn remove(&self, dev: &PCIDevice);
+}
+
+struct Binding {
+    address: PCIDeviceAddress,
+    driver: usize,
+}
+
+lazy_static! {
+    static ref DRIVERS: Mutex<Vec<Box<dyn PCIDriver + Sync + Send>>> = Mutex::new(Vec::new());
+    static ref BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+}
+
+pub fn register(driver: impl PCIDriver + Sync + Send + 'static) {
+    DRIVERS.lock().push(box driver);
+}
+
+pub fn bound_driver(address: &PCIDeviceAddress) -> Option<&'static str> {
+    let binding = BINDINGS
+        .lock()
+        .iter()
+        .find(|binding| binding.address == *address)
+        .map(|binding| binding.driver)?;
+    Some(DRIVERS.lock()[binding].name())
+}
+
+pub fn bind_all() {
+    println!("\nPCI DRIVERS");
+    let drivers = DRIVERS.lock();
+
+    for dev in devices() {
+        if BINDINGS.lock().iter().any(|b| b.address == dev.address) {
+            continue;
+        }
+
+        for (idx, driver) in drivers.iter().enumerate() {
+            if !driver
+                .patterns()
+                .iter()
+                .any(|find| find.matches(&dev.id, &dev.dev_type))
+            {
+                continue;
+            }
+
+            print!("Binding {} to {}", dev.address, driver.name());
+            match driver.probe(dev) {
+                Ok(()) => {
+                    ok!();
+                    BINDINGS.lock().push(Binding {
+                        address: dev.address,
+                        driver: idx,
+                    });
+                    break;
+                }
+                Err(err) => {
+                    fail!();
+                    error!(
+                        target: "pci",
+                        "{} {} probe failed: {}",
+                        dev.address,
+                        driver.name(),
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+pub fn unbind(address: &PCIDeviceAddress) -> Result<(), &'static str> {
+    let dev = devices()
+        .iter()
+        .find(|dev| dev.address == *address)
+        .ok_or("Device is not enumerated")?;
+
+    // Same lock order as `bind_all`, DRIVERS before BINDINGS
+    let drivers = DRIVERS.lock();
+    let binding = {
+        let mut bindings = BINDINGS.lock();
+        let idx = bindings
+            .iter()
+            .position(|binding| binding.address == *address)
+            .ok_or("Device is not bound")?;
+        bindings.remove(idx)
+    };
+    drivers[binding.driver].remove(dev);
+    Ok(())
+}
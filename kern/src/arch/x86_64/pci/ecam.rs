@@ -0,0 +1,86 @@
+use super::PCIDeviceAddress;
+use crate::arch::{
+    acpi::{self, mcfg::McfgEntry},
+    mem::vmm::{self, CacheMode, Mapping},
+};
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::PhysAddr;
+
+const BUS_SIZE: u64 = 1 << 20;
+
+enum Bus {
+    Unmapped,
+    Mapped(Mapping),
+    // Mapping it failed once, the legacy ports are used from then on
+    Failed,
+}
+
+struct Ecam {
+    region: McfgEntry,
+    buses: Vec<Bus>,
+}
+
+lazy_static! {
+    static ref ECAM: Mutex<Option<Ecam>> = Mutex::new(None);
+}
+
+pub fn init() {
+    let region = acpi::info()
+        .mcfg
+        .iter()
+        .find(|entry| entry.segment == 0)
+        .copied();
+
+    match region {
+        Some(region) => {
+            print!(
+                "PCIe ECAM at {:#x} (buses {}-{})",
+                region.base, region.start_bus, region.end_bus
+            );
+            ok!();
+            *ECAM.lock() = Some(Ecam {
+                region,
+                buses: (0..256).map(|_| Bus::Unmapped).collect(),
+            });
+        }
+        None => println!("No MCFG table, using legacy PCI configuration ports"),
+    }
+}
+
+/// Returns the address of `offset` in the ECAM window, mapping the bus on first use.
+pub(super) fn config_ptr(address: &PCIDeviceAddress, offset: u16) -> Option<u64> {
+    assert!(offset < 0x1000);
+
+    let mut ecam = ECAM.lock();
+    let ecam = ecam.as_mut()?;
+    if address.bus < ecam.region.start_bus || address.bus > ecam.region.end_bus {
+        return None;
+    }
+
+    let bus = &mut ecam.buses[address.bus as usize];
+    if let Bus::Unmapped = bus {
+        // The MCFG base address is that of bus 0, even when the range starts later
+        let bus_base = ecam.region.base + address.bus as u64 * BUS_SIZE;
+        *bus = match vmm::map_phys(
+            PhysAddr::new(bus_base),
+            BUS_SIZE,
+            CacheMode::Uncached,
+            "ecam",
+        ) {
+            Ok(mapping) => Bus::Mapped(mapping),
+            Err(e) => {
+                error!("ECAM bus {}: {}", address.bus, e);
+                Bus::Failed
+            }
+        };
+    }
+    let bus_base = match bus {
+        Bus::Mapped(mapping) => mapping.addr().as_u64(),
+        _ => return None,
+    };
+
+    Some(bus_base + ((address.slot as u64) << 15 | (address.func as u64) << 12 | offset as u64))
+}
@@ -1,31 +1,60 @@
+use super::mem::vmm::{self, CacheMode, Mapping};
+use crate::schema::sys::SysSchema;
+
+use alloc::{format, string::String, vec::Vec};
+use core::{fmt, ptr};
 use lazy_static::lazy_static;
-use spinning::Mutex;
+use spinning::{Mutex, MutexGuard, Once};
 use x86_64::{instructions::port::Port, PhysAddr};
 
+pub mod capability;
+pub mod driver;
+pub mod ecam;
+pub mod msi;
+
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
 lazy_static! {
     static ref PCI_CONFIG_ADDRESS: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_ADDRESS));
-    static ref PCI_CONFIG_DATA: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_DATA));
 }
 
-const PCIFIELD_VENDOR_ID: u8 = 0x00;
-const PCIFIELD_DEVICE_ID: u8 = 0x02;
-const PCIFIELD_REVISION_ID: u8 = 0x08;
-const PCIFIELD_PROG_IF: u8 = 0x09;
-const PCIFIELD_SUBCLASS: u8 = 0x0A;
-const PCIFIELD_CLASS: u8 = 0x0B;
-const PCIFIELD_HHEADER_TYPE: u8 = 0x0E;
-const PCIFIELD_SECONDARY_BUS_NUMBER: u8 = 0x19;
+static PCI_DEVICES: Once<Vec<PCIDevice>> = Once::new();
 
-#[derive(Debug, Clone, Copy)]
+const PCIFIELD_VENDOR_ID: u16 = 0x00;
+const PCIFIELD_DEVICE_ID: u16 = 0x02;
+const PCIFIELD_COMMAND: u16 = 0x04;
+const PCIFIELD_REVISION_ID: u16 = 0x08;
+const PCIFIELD_PROG_IF: u16 = 0x09;
+const PCIFIELD_SUBCLASS: u16 = 0x0A;
+const PCIFIELD_CLASS: u16 = 0x0B;
+const PCIFIELD_HHEADER_TYPE: u16 = 0x0E;
+const PCIFIELD_SECONDARY_BUS_NUMBER: u16 = 0x19;
+const PCIFIELD_INTERRUPT_LINE: u16 = 0x3C;
+
+const PCICOMMAND_IO_SPACE: u16 = 1 << 0;
+const PCICOMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const PCICOMMAND_BUS_MASTER: u16 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PCIDeviceAddress {
     bus: u8,
     slot: u8,
     func: u8,
 }
 
+impl PCIDeviceAddress {
+    pub fn new(bus: u8, slot: u8, func: u8) -> Self {
+        Self { bus, slot, func }
+    }
+}
+
+impl fmt::Display for PCIDeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.slot, self.func)
+    }
+}
+
 impl From<&PCIDeviceAddress> for u32 {
     fn from(addr: &PCIDeviceAddress) -> Self {
         assert!(addr.slot < 1 << 5);
@@ -65,7 +94,7 @@ impl PCIDeviceID {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct PCIFind {
     vendor_id: u16,
     device_id: u16,
@@ -75,20 +104,45 @@ pub struct PCIFind {
     rev_id: u8,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub struct PCIDeviceType {
-    class_id: u8,
-    subclass_id: u8,
-    prog_if: u8,
-    rev_id: u8,
+    pub class_id: u8,
+    pub subclass_id: u8,
+    pub prog_if: u8,
+    pub rev_id: u8,
+}
+
+pub fn init() {
+    ecam::init();
+    PCI_DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        PCIDevice::scan_bus(0, &mut devices);
+        devices
+    });
+
+    for dev in devices() {
+        debug!(
+            "{} {:04x}:{:04x} (class: {:#04x}; subclass: {:#04x})",
+            dev.address,
+            dev.id.vendor_id,
+            dev.id.device_id,
+            dev.dev_type.class_id,
+            dev.dev_type.subclass_id
+        );
+    }
+    print!("PCI enumerated ({} devices)", devices().len());
+    ok!();
+}
+
+pub fn devices() -> &'static [PCIDevice] {
+    PCI_DEVICES.wait()
 }
 
-#[repr(C)]
-union PCIData {
-    val8: [u8; 4],
-    val16: [u16; 2],
-    val32: u32,
+pub fn register_sys(sys: &mut SysSchema) {
+    for dev in devices() {
+        let dev = *dev;
+        sys.insert_text(&format!("pci/{}", dev.address), move || dev.describe());
+    }
 }
 
 impl PCIFind {
@@ -103,6 +157,27 @@ impl PCIFind {
         }
     }
 
+    pub fn class(class_id: u8, subclass_id: u8) -> Self {
+        PCIFind {
+            vendor_id: 0xFFFFu16,
+            device_id: 0xFFFFu16,
+            class_id,
+            subclass_id,
+            prog_if: 0xFFu8,
+            rev_id: 0xFFu8,
+        }
+    }
+
+    pub fn with_prog_if(mut self, prog_if: u8) -> Self {
+        self.prog_if = prog_if;
+        self
+    }
+
+    pub fn with_rev_id(mut self, rev_id: u8) -> Self {
+        self.rev_id = rev_id;
+        self
+    }
+
     fn matches(&self, id: &PCIDeviceID, dev_type: &PCIDeviceType) -> bool {
         if id.vendor_id == 0xFFFF && id.device_id == 0xFFFF {
             return false;
@@ -134,58 +209,131 @@ pub struct PCIDevice {
     pub address: PCIDeviceAddress,
     id: PCIDeviceID,
     dev_type: PCIDeviceType,
+    bars: [Option<PCIBAR>; 6],
 }
 
 #[allow(unused)]
 impl PCIDevice {
-    pub unsafe fn pci_read32(address: &PCIDeviceAddress, offset: u8) -> u32 {
+    /// Selects `offset` through the legacy ports. They can't reach extended config space, reads
+    /// of it give all ones like an absent device and writes are dropped.
+    unsafe fn port_select(
+        address: &PCIDeviceAddress,
+        offset: u16,
+    ) -> Option<MutexGuard<Port<u32>>> {
+        if offset >= 0x100 {
+            return None;
+        }
+        let mut port = PCI_CONFIG_ADDRESS.lock();
+        port.write(u32::from(address) | (offset & !0x3) as u32);
+        Some(port)
+    }
+
+    pub unsafe fn pci_read32(address: &PCIDeviceAddress, offset: u16) -> u32 {
         assert!(offset & 0x3 == 0);
-        PCI_CONFIG_ADDRESS
-            .lock()
-            .write(u32::from(address) + offset as u32);
-        PCI_CONFIG_DATA.lock().read()
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::read_volatile(ptr as *const u32);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return !0,
+        };
+        Port::<u32>::new(CONFIG_DATA).read()
     }
 
-    pub unsafe fn pci_write32(address: &PCIDeviceAddress, offset: u8, val: u32) {
+    pub unsafe fn pci_write32(address: &PCIDeviceAddress, offset: u16, val: u32) {
         assert!(offset & 0x3 == 0);
-        PCI_CONFIG_ADDRESS
-            .lock()
-            .write(u32::from(address) + offset as u32);
-        PCI_CONFIG_DATA.lock().write(val);
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::write_volatile(ptr as *mut u32, val);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return,
+        };
+        Port::<u32>::new(CONFIG_DATA).write(val);
+    }
+
+    pub unsafe fn pci_read16(address: &PCIDeviceAddress, offset: u16) -> u16 {
+        assert!(offset & 0x1 == 0);
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::read_volatile(ptr as *const u16);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return !0,
+        };
+        Port::<u16>::new(CONFIG_DATA + (offset & 0x2)).read()
     }
 
-    pub unsafe fn pci_read16(address: &PCIDeviceAddress, offset: u8) -> u16 {
+    pub unsafe fn pci_write16(address: &PCIDeviceAddress, offset: u16, val: u16) {
         assert!(offset & 0x1 == 0);
-        let aligned_offset = offset & !0x3;
-        let data_raw = PCIDevice::pci_read32(address, aligned_offset);
-        let data: PCIData = PCIData { val32: data_raw };
-        data.val8[(offset & 0x3) as usize] as u16
-            | ((data.val8[(offset & 0x3) as usize + 1] as u16) << 8)
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::write_volatile(ptr as *mut u16, val);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return,
+        };
+        Port::<u16>::new(CONFIG_DATA + (offset & 0x2)).write(val);
+    }
+
+    pub unsafe fn pci_read8(address: &PCIDeviceAddress, offset: u16) -> u8 {
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::read_volatile(ptr as *const u8);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return !0,
+        };
+        Port::<u8>::new(CONFIG_DATA + (offset & 0x3)).read()
     }
 
-    pub unsafe fn pci_read8(address: &PCIDeviceAddress, offset: u8) -> u8 {
-        let aligned_offset = offset & !0x3;
-        let data_raw = PCIDevice::pci_read32(address, aligned_offset);
-        let data: PCIData = PCIData { val32: data_raw };
-        data.val8[(offset & 0x3) as usize]
+    pub unsafe fn pci_write8(address: &PCIDeviceAddress, offset: u16, val: u8) {
+        if let Some(ptr) = ecam::config_ptr(address, offset) {
+            return ptr::write_volatile(ptr as *mut u8, val);
+        }
+        let _select = match PCIDevice::port_select(address, offset) {
+            Some(select) => select,
+            None => return,
+        };
+        Port::<u8>::new(CONFIG_DATA + (offset & 0x3)).write(val);
     }
 
-    pub fn read8(&self, offset: u8) -> u8 {
+    pub fn read8(&self, offset: u16) -> u8 {
         unsafe { PCIDevice::pci_read8(&self.address, offset) }
     }
 
-    pub fn read16(&self, offset: u8) -> u16 {
+    pub fn read16(&self, offset: u16) -> u16 {
         unsafe { PCIDevice::pci_read16(&self.address, offset) }
     }
 
-    pub fn read32(&self, offset: u8) -> u32 {
+    pub fn read32(&self, offset: u16) -> u32 {
         unsafe { PCIDevice::pci_read32(&self.address, offset) }
     }
 
-    pub fn write32(&self, offset: u8, val: u32) {
+    pub fn write8(&self, offset: u16, val: u8) {
+        unsafe { PCIDevice::pci_write8(&self.address, offset, val) }
+    }
+
+    pub fn write16(&self, offset: u16, val: u16) {
+        unsafe { PCIDevice::pci_write16(&self.address, offset, val) }
+    }
+
+    pub fn write32(&self, offset: u16, val: u32) {
         unsafe { PCIDevice::pci_write32(&self.address, offset, val) }
     }
 
+    pub fn interrupt_line(&self) -> u8 {
+        self.read8(PCIFIELD_INTERRUPT_LINE)
+    }
+
+    pub fn enable_bus_mastering(&self) {
+        let command = self.read16(PCIFIELD_COMMAND);
+        self.write16(
+            PCIFIELD_COMMAND,
+            command | PCICOMMAND_IO_SPACE | PCICOMMAND_MEMORY_SPACE | PCICOMMAND_BUS_MASTER,
+        );
+    }
+
     fn get_id(address: &PCIDeviceAddress) -> PCIDeviceID {
         PCIDeviceID {
             device_id: unsafe { PCIDevice::pci_read16(address, PCIFIELD_DEVICE_ID) },
@@ -202,89 +350,123 @@ impl PCIDevice {
         }
     }
 
-    fn matches_pattern(address: &PCIDeviceAddress, pattern: &PCIFind) -> Option<PCIDevice> {
-        let id = PCIDevice::get_id(&address.clone());
-        if !id.is_valid() {
-            return None;
-        }
-        let dev_type = PCIDevice::get_type(&address.clone());
-        if pattern.matches(&id, &dev_type) {
-            return Some(PCIDevice {
-                address: address.clone(),
-                id,
-                dev_type,
-            });
+    fn scan_bus(bus: u8, devices: &mut Vec<PCIDevice>) {
+        for slot in 0..32 {
+            let addr = PCIDeviceAddress { bus, slot, func: 0 };
+            if !PCIDevice::get_id(&addr).is_valid() {
+                continue;
+            }
+
+            let header = unsafe { PCIDevice::pci_read8(&addr, PCIFIELD_HHEADER_TYPE) };
+            let num_func = if header & 0x80 == 0x80 { 8 } else { 1 };
+            for func in 0..num_func {
+                PCIDevice::scan_function(&PCIDeviceAddress { bus, slot, func }, devices);
+            }
         }
-        None
     }
 
-    fn find_on_bus(bus: u8, find: &PCIFind, last: Option<u32>) -> Option<PCIDevice> {
-        println!(
-            "Searching for PCI device {:#x}:{:#x} on bus {bus}...",
-            find.vendor_id,
-            find.device_id,
-            bus = bus
-        );
+    fn scan_function(address: &PCIDeviceAddress, devices: &mut Vec<PCIDevice>) {
+        let id = PCIDevice::get_id(address);
+        if !id.is_valid() {
+            return;
+        }
 
-        let mut next_device = 0u32;
-        let mut found_device = None;
+        let header = unsafe { PCIDevice::pci_read8(address, PCIFIELD_HHEADER_TYPE) } & 0x7f;
+        let mut device = PCIDevice {
+            address: *address,
+            id,
+            dev_type: PCIDevice::get_type(address),
+            bars: [None; 6],
+        };
+        device.bars = match header {
+            0x0 => device.read_bars(6),
+            0x1 => device.read_bars(2),
+            _ => [None; 6],
+        };
+        devices.push(device);
 
-        for slot in 0..32 {
-            let mut num_func = 1u32;
-            let mut func = 0;
-            while func < num_func {
-                let ref addr = PCIDeviceAddress {
-                    bus,
-                    slot,
-                    func: func as u8,
-                };
-                let devaddr: u32 = addr.clone().into();
-                if last.unwrap_or(0u32) < devaddr
-                    && (found_device.is_none() || devaddr < next_device)
-                {
-                    if let Some(dev) = PCIDevice::matches_pattern(addr, find) {
-                        found_device = Some(dev);
-                        next_device = devaddr;
-                    }
-                }
-                let header = unsafe { PCIDevice::pci_read8(&addr, PCIFIELD_HHEADER_TYPE) };
-                if header & 0x80 == 0x80 {
-                    num_func = 8;
-                }
-                if (header & 0x7f) == 0x1 {
-                    let sub_bus_id =
-                        unsafe { PCIDevice::pci_read8(&addr, PCIFIELD_SECONDARY_BUS_NUMBER) };
-                    let rec_ret = PCIDevice::find_on_bus(sub_bus_id, find, last);
-                    let ref addr = rec_ret.unwrap().address;
-                    if last.unwrap_or(0) < addr.into()
-                        && (found_device.is_none() || u32::from(addr) < next_device)
-                    {
-                        found_device = rec_ret;
-                    }
-                }
-                func += 1;
+        if header == 0x1 {
+            let sub_bus_id = device.read8(PCIFIELD_SECONDARY_BUS_NUMBER);
+            if sub_bus_id > address.bus {
+                PCIDevice::scan_bus(sub_bus_id, devices);
             }
         }
+    }
+
+    fn read_bars(&self, count: u8) -> [Option<PCIBAR>; 6] {
+        let mut bars = [None; 6];
+        let mut bar = 0;
+
+        while bar < count {
+            if self.read32(0x10 + 4 * bar as u16) == 0 {
+                bar += 1;
+                continue;
+            }
 
-        if let Some(device) = found_device {
-            println!(
-                "Found device {} (class: {cid}; subclass: {scid}) on bus {bus} (slot: {slot}; func: {func}).",
-                cid=device.dev_type.class_id,
-                scid=device.dev_type.subclass_id,
-                bus=device.address.bus,
-                slot=device.address.slot,
-                func=device.address.func
-            );
+            let res = self.get_bar(bar);
+            let is_64bit = res.is_64bit();
+            bars[bar as usize] = Some(res);
+            bar += if is_64bit { 2 } else { 1 };
         }
 
-        found_device
+        bars
     }
 
     pub fn search(find: &PCIFind, last: Option<u32>) -> Option<PCIDevice> {
-        PCIDevice::find_on_bus(0, find, last)
+        devices()
+            .iter()
+            .filter(|dev| last.map_or(true, |last| last < u32::from(dev.address)))
+            .find(|dev| find.matches(&dev.id, &dev.dev_type))
+            .copied()
+    }
+
+    pub fn id(&self) -> PCIDeviceID {
+        self.id
+    }
+
+    pub fn dev_type(&self) -> PCIDeviceType {
+        self.dev_type
+    }
+
+    pub fn bar(&self, bar: u8) -> Option<&PCIBAR> {
+        self.bars.get(bar as usize)?.as_ref()
+    }
+
+    fn describe(&self) -> String {
+        let mut res = format!(
+            "address: {}\nvendor: {:#06x}\ndevice: {:#06x}\nclass: {:#04x}\nsubclass: {:#04x}\nprog_if: {:#04x}\nrevision: {:#04x}\n",
+            self.address,
+            self.id.vendor_id,
+            self.id.device_id,
+            self.dev_type.class_id,
+            self.dev_type.subclass_id,
+            self.dev_type.prog_if,
+            self.dev_type.rev_id,
+        );
+        let caps = self.capabilities();
+        if !caps.is_empty() {
+            let names: Vec<String> = caps.iter().map(|cap| format!("{:?}", cap.kind)).collect();
+            res.push_str(&format!("capabilities: {}\n", names.join(", ")));
+        }
+        if let Some(name) = driver::bound_driver(&self.address) {
+            res.push_str(&format!("driver: {}\n", name));
+        }
+        for (i, bar) in self.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                res.push_str(&format!(
+                    "bar{}: {} {:#x} size {:#x}\n",
+                    i,
+                    if bar.is_iospace() { "io" } else { "mmio" },
+                    bar.addr(),
+                    bar.size()
+                ));
+            }
+        }
+        res
     }
 
     pub fn get_bar(&self, bar: u8) -> PCIBAR {
+        let bar = bar as u16;
         let lo = self.read32(0x10 + 4 * (bar + 0));
 
         let mut res = PCIBAR {
@@ -328,6 +510,7 @@ const PCIBAR_TYPE_16BIT: u8 = 0x1 << 1 | 0x0 << 0;
 const PCIBAR_TYPE_32BIT: u8 = 0x0 << 1 | 0x0 << 0;
 const PCIBAR_TYPE_64BIT: u8 = 0x2 << 1 | 0x0 << 0;
 
+#[derive(Debug, Clone, Copy)]
 pub struct PCIBAR {
     addr_raw: u64,
     size_raw: u64,
@@ -373,24 +556,12 @@ impl PCIBAR {
         self.is_16bit() || self.is_32bit() || self.is_64bit()
     }
 
-    pub fn identity_map(&self) -> Result<(), &'static str> {
-        use x86_64::structures::paging::PageTableFlags;
-
+    /// Maps the BAR into kernel address space, it stays mapped for as long as the returned value lives.
+    pub fn map(&self, cache: CacheMode) -> Result<Mapping, &'static str> {
         if !self.is_mmio() {
             return Err("BAR is not mmio");
         }
 
-        let addr = self.addr();
-        let size = self.size();
-
-        super::mem::paging::identity_map(
-            PhysAddr::new(addr),
-            PhysAddr::new(addr + size),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-            false,
-        )
-        .ok();
-
-        Ok(())
+        vmm::map_phys(PhysAddr::new(self.addr()), self.size(), cache, "pci-bar")
     }
 }
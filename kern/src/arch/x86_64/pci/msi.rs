@@ -0,0 +1,151 @@
+use super::{capability::PCICapabilityKind, PCIDevice, PCIFIELD_COMMAND};
+use crate::arch::{
+    idt, lapic,
+    mem::vmm::{self, CacheMode, Mapping},
+};
+
+use core::ptr;
+use x86_64::PhysAddr;
+
+const PCICOMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTI_ENABLE: u16 = 0x7 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+fn msi_address() -> u32 {
+    MSI_ADDRESS_BASE | (lapic::id() as u32) << 12
+}
+
+impl PCIDevice {
+    fn read_cap_control(&self, cap: u16) -> u16 {
+        self.read16(cap + 0x2)
+    }
+
+    fn write_cap_control(&self, cap: u16, control: u16) {
+        self.write16(cap + 0x2, control);
+    }
+
+    fn disable_intx(&self) {
+        let command = self.read16(PCIFIELD_COMMAND);
+        self.write16(PCIFIELD_COMMAND, command | PCICOMMAND_INTX_DISABLE);
+    }
+
+    /// Routes a single MSI message to a freshly allocated vector and returns it.
+    pub fn enable_msi(
+        &self,
+        name: &'static str,
+        handler: impl Fn() + Send + Sync + 'static,
+    ) -> Result<u8, &'static str> {
+        let cap = self
+            .find_capability(PCICapabilityKind::Msi)
+            .ok_or("Device does not support MSI")?;
+        let vector = idt::allocate_vector(name, handler).ok_or("No free interrupt vectors")?;
+
+        let control = self.read_cap_control(cap);
+        self.write32(cap + 0x4, msi_address());
+        if control & MSI_CONTROL_64BIT != 0 {
+            self.write32(cap + 0x8, 0);
+            self.write16(cap + 0xC, vector as u16);
+        } else {
+            self.write16(cap + 0x8, vector as u16);
+        }
+
+        self.write_cap_control(
+            cap,
+            (control & !MSI_CONTROL_MULTI_ENABLE) | MSI_CONTROL_ENABLE,
+        );
+        self.disable_intx();
+        Ok(vector)
+    }
+
+    pub fn disable_msi(&self, vector: u8) {
+        if let Some(cap) = self.find_capability(PCICapabilityKind::Msi) {
+            let control = self.read_cap_control(cap);
+            self.write_cap_control(cap, control & !MSI_CONTROL_ENABLE);
+        }
+        idt::free_vector(vector);
+    }
+
+    pub fn msix_table_size(&self) -> Option<u16> {
+        let cap = self.find_capability(PCICapabilityKind::MsiX)?;
+        Some((self.read_cap_control(cap) & MSIX_CONTROL_TABLE_SIZE) + 1)
+    }
+
+    fn msix_entry(&self, cap: u16, entry: u16) -> Result<Mapping, &'static str> {
+        let table = self.read32(cap + 0x4);
+        let bar = self
+            .bar((table & 0x7) as u8)
+            .ok_or("MSI-X table BAR is not implemented")?;
+        if !bar.is_mmio() {
+            return Err("MSI-X table BAR is not mmio");
+        }
+
+        let addr = bar.addr() + (table & !0x7) as u64 + entry as u64 * MSIX_ENTRY_SIZE;
+        vmm::map_phys(
+            PhysAddr::new(addr),
+            MSIX_ENTRY_SIZE,
+            CacheMode::Uncached,
+            "msix-entry",
+        )
+    }
+
+    /// Routes MSI-X table entry `entry` to a freshly allocated vector and returns it.
+    pub fn enable_msix(
+        &self,
+        entry: u16,
+        name: &'static str,
+        handler: impl Fn() + Send + Sync + 'static,
+    ) -> Result<u8, &'static str> {
+        let cap = self
+            .find_capability(PCICapabilityKind::MsiX)
+            .ok_or("Device does not support MSI-X")?;
+        if entry >= self.msix_table_size().unwrap_or(0) {
+            return Err("MSI-X entry out of range");
+        }
+
+        let mapping = self.msix_entry(cap, entry)?;
+        let addr = mapping.addr().as_u64();
+        let vector = idt::allocate_vector(name, handler).ok_or("No free interrupt vectors")?;
+
+        let control = self.read_cap_control(cap);
+        self.write_cap_control(
+            cap,
+            control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+        );
+        unsafe {
+            ptr::write_volatile(addr as *mut u32, msi_address());
+            ptr::write_volatile((addr + 0x4) as *mut u32, 0);
+            ptr::write_volatile((addr + 0x8) as *mut u32, vector as u32);
+            ptr::write_volatile((addr + 0xC) as *mut u32, 0);
+        }
+        self.write_cap_control(
+            cap,
+            (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK,
+        );
+        self.disable_intx();
+        Ok(vector)
+    }
+
+    pub fn disable_msix(&self, entry: u16, vector: u8) {
+        if let Some(cap) = self.find_capability(PCICapabilityKind::MsiX) {
+            if let Ok(mapping) = self.msix_entry(cap, entry) {
+                let addr = mapping.addr().as_u64();
+                unsafe {
+                    let ctrl = ptr::read_volatile((addr + 0xC) as *const u32);
+                    ptr::write_volatile((addr + 0xC) as *mut u32, ctrl | MSIX_VECTOR_MASKED);
+                }
+            }
+        }
+        idt::free_vector(vector);
+    }
+}
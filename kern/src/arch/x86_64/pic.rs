@@ -1,30 +1,47 @@
 use pic8259_simple::ChainedPics;
+use x86_64::instructions::port::Port;
 
 pub const PIC_1_OFFS: u8 = 32;
 pub const PIC_2_OFFS: u8 = PIC_1_OFFS + 8;
 
-#[derive(Debug, Copy, Clone)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFS,
-    Keyboard,
-    Mouse = PIC_1_OFFS + 12,
-}
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFS, PIC_2_OFFS) });
 
-impl From<InterruptIndex> for u8 {
-    fn from(ii: InterruptIndex) -> u8 {
-        ii as u8
+pub fn unmask(irq: u8) {
+    set_masked(irq, false);
+    if irq >= 8 {
+        set_masked(2, false);
     }
 }
 
-impl From<InterruptIndex> for usize {
-    fn from(ii: InterruptIndex) -> usize {
-        ii as usize
+pub fn mask(irq: u8) {
+    set_masked(irq, true);
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    assert!(irq < 16);
+    let mut port: Port<u8> = match irq {
+        0..=7 => Port::new(0x21),
+        _ => Port::new(0xA1),
+    };
+    let bit = 1 << (irq % 8);
+
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | bit } else { mask & !bit });
     }
 }
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFS, PIC_2_OFFS) });
+/// Masks every line on both PICs, used once the IOAPIC takes over.
+pub fn disable() {
+    let mut master: Port<u8> = Port::new(0x21);
+    let mut slave: Port<u8> = Port::new(0xA1);
+
+    unsafe {
+        master.write(0xFF);
+        slave.write(0xFF);
+    }
+}
 
 pub fn init() {
     unsafe {
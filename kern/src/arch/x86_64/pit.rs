@@ -0,0 +1,41 @@
+use super::irq;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+pub const TICK_HZ: u64 = 1000;
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// Channel 0, lobyte/hibyte, rate generator
+const MODE_RATE: u8 = 0b0011_0100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::time::expire_timers();
+}
+
+/// Programs channel 0 to fire `TICK_HZ` times a second and starts counting.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL0);
+    unsafe {
+        command.write(MODE_RATE);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+
+    check_ok!(
+        format_args!("PIT running at {} Hz", TICK_HZ),
+        irq::register(0, "timer", tick)
+    );
+}
+
+/// Timer interrupts since `init`, one every millisecond. `time::Instant` is more precise.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,237 @@
+use super::{
+    acpi::{
+        self,
+        fadt::{
+            GenericAddress, FADT_RESET_REG_SUP, GAS_PCI_CONFIG, GAS_SYSTEM_IO, GAS_SYSTEM_MEMORY,
+        },
+    },
+    mem::vmm::{self, CacheMode},
+    pci::{PCIDevice, PCIDeviceAddress},
+    smp, tsc,
+};
+use crate::schema::sys::SysSchema;
+
+use core::{ptr, time::Duration};
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    PhysAddr,
+};
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_TYP_MASK: u16 = 0x7;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+// QEMU, Bochs and older QEMU, VirtualBox
+const EMULATOR_SHUTDOWN: [(u16, u16); 3] = [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+
+// How long each method gets to take effect before the next one is tried
+const SETTLE: Duration = Duration::from_millis(500);
+
+fn write_reset_reg(reg: &GenericAddress, value: u8) -> Result<(), &'static str> {
+    match reg.address_space {
+        GAS_SYSTEM_IO => unsafe { Port::<u8>::new(reg.address as u16).write(value) },
+        GAS_SYSTEM_MEMORY => {
+            let regs = vmm::map_phys(PhysAddr::new(reg.address), 1, CacheMode::Uncached, "reset")?;
+            unsafe { ptr::write_volatile(regs.addr().as_mut_ptr::<u8>(), value) };
+        }
+        GAS_PCI_CONFIG => {
+            // Bus 0, the device and function are packed above the register offset
+            let address = reg.address;
+            let addr = PCIDeviceAddress::new(0, (address >> 32) as u8, (address >> 16) as u8);
+            unsafe { PCIDevice::pci_write8(&addr, address as u16, value) };
+        }
+        _ => return Err("Unsupported reset register address space"),
+    }
+    Ok(())
+}
+
+fn reset_acpi() -> Result<(), &'static str> {
+    let fadt = acpi::info().fadt.ok_or("No FADT")?;
+    if fadt.header.revision < 2 || fadt.flags & FADT_RESET_REG_SUP == 0 {
+        return Err("No ACPI reset register");
+    }
+    write_reset_reg(&fadt.reset_reg, fadt.reset_value)
+}
+
+fn reset_kbc() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    unsafe {
+        for _ in 0..0x10000 {
+            if status.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        command.write(KBC_PULSE_RESET);
+    }
+}
+
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { lidt(&empty) };
+    // With no IDT the breakpoint escalates to a double and then a triple fault
+    interrupts::int3();
+    unreachable!();
+}
+
+/// Resets the machine through ACPI, then the keyboard controller, then by triple faulting.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    warn!("Rebooting");
+    smp::stop_others();
+
+    if reset_acpi().is_ok() {
+        tsc::delay(SETTLE);
+    }
+    reset_kbc();
+    tsc::delay(SETTLE);
+    triple_fault()
+}
+
+/// Reads an integer term: Zero, One or a byte/word constant.
+fn aml_integer(aml: &[u8], pos: &mut usize) -> Option<u16> {
+    let op = *aml.get(*pos)?;
+    *pos += 1;
+    match op {
+        AML_ZERO_OP => Some(0),
+        AML_ONE_OP => Some(1),
+        AML_BYTE_PREFIX => {
+            *pos += 1;
+            Some(*aml.get(*pos - 1)? as u16)
+        }
+        AML_WORD_PREFIX => {
+            *pos += 2;
+            let bytes = aml.get(*pos - 2..*pos)?;
+            Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+        }
+        _ => None,
+    }
+}
+
+/// Finds `Name(\_S5, Package() { SLP_TYPa, SLP_TYPb, ... })` in a table's AML.
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let named = |i: usize| match i {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => {
+            aml[i - 1] == AML_NAME_OP || (aml[i - 1] == AML_ROOT_CHAR && aml[i - 2] == AML_NAME_OP)
+        }
+    };
+    let name = aml
+        .windows(4)
+        .enumerate()
+        .position(|(i, name)| name == b"_S5_" && named(i))?;
+
+    let mut pos = name + 4;
+    if *aml.get(pos)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength, the top two bits of its lead byte count the bytes that follow
+    pos += 1;
+    pos += 1 + (*aml.get(pos)? >> 6) as usize;
+    // NumElements
+    pos += 1;
+
+    let slp_typa = aml_integer(aml, &mut pos)?;
+    let slp_typb = aml_integer(aml, &mut pos)?;
+    Some((slp_typa, slp_typb))
+}
+
+fn sleep_types() -> Option<(u16, u16)> {
+    let fadt = acpi::info().fadt?;
+    let dsdt = acpi::table_bytes(fadt.dsdt_address());
+    parse_s5(dsdt).or_else(|| {
+        acpi::tables()
+            .iter()
+            .filter(|addr| &acpi::header(**addr).signature == b"SSDT")
+            .find_map(|addr| parse_s5(acpi::table_bytes(*addr)))
+    })
+}
+
+fn enable_acpi() -> Result<(), &'static str> {
+    let fadt = acpi::info().fadt.ok_or("No FADT")?;
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_cnt_blk as u16);
+    if unsafe { pm1a.read() } & PM1_SCI_EN != 0 {
+        return Ok(());
+    }
+    if fadt.smi_cmd == 0 || fadt.acpi_enable == 0 {
+        return Err("ACPI can not be enabled");
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_cmd as u16).write(fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { pm1a.read() } & PM1_SCI_EN != 0 {
+            return Ok(());
+        }
+        tsc::delay(Duration::from_millis(10));
+    }
+    Err("Firmware did not hand over ACPI")
+}
+
+fn shutdown_acpi() -> Result<(), &'static str> {
+    let fadt = acpi::info().fadt.ok_or("No FADT")?;
+    if fadt.pm1a_cnt_blk == 0 {
+        return Err("No PM1a control block");
+    }
+    let (slp_typa, slp_typb) = sleep_types().ok_or("No \\_S5 object")?;
+    enable_acpi()?;
+
+    unsafe {
+        let mut pm1a: Port<u16> = Port::new(fadt.pm1a_cnt_blk as u16);
+        let value = pm1a.read() & PM1_SCI_EN;
+        pm1a.write(value | ((slp_typa & PM1_SLP_TYP_MASK) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+        if fadt.pm1b_cnt_blk != 0 {
+            let mut pm1b: Port<u16> = Port::new(fadt.pm1b_cnt_blk as u16);
+            let value = pm1b.read() & PM1_SCI_EN;
+            pm1b.write(value | ((slp_typb & PM1_SLP_TYP_MASK) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+        }
+    }
+    Ok(())
+}
+
+/// Enters S5 soft-off, falling back to the shutdown ports of common emulators.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    warn!("Powering off");
+    smp::stop_others();
+
+    // A stopped CPU may have been holding the console locks
+    match shutdown_acpi() {
+        Ok(()) => tsc::delay(SETTLE),
+        Err(e) => emergency_println!("ACPI soft-off unavailable: {}", e),
+    }
+    for &(port, value) in EMULATOR_SHUTDOWN.iter() {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    tsc::delay(SETTLE);
+
+    emergency_println!("It is now safe to turn off your computer");
+    super::hlt_loop()
+}
+
+pub fn register_sys(sys: &mut SysSchema) {
+    sys.insert_writable(
+        "power",
+        || b"reboot poweroff\n".to_vec(),
+        |buf| match core::str::from_utf8(buf).map(str::trim) {
+            Ok("reboot") => reboot(),
+            Ok("poweroff") | Ok("shutdown") => shutdown(),
+            _ => Err("Expected reboot or poweroff"),
+        },
+    );
+}
@@ -8,3 +8,27 @@ macro_rules! println {
 macro_rules! print {
     ($($arg:tt)*) => ($crate::arch::arch::_print(format_args!($($arg)*)));
 }
+
+/// Like `print!`, for interactive output that has no place in the kernel log.
+#[macro_export]
+macro_rules! console_print {
+    ($($arg:tt)*) => ($crate::arch::arch::_console_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! console_println {
+    () => (console_print!("\n"));
+    ($($arg:tt)*) => (console_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Like `print!`, for exception and panic paths that may have interrupted a console lock holder.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::arch::arch::_emergency_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! emergency_println {
+    () => (emergency_print!("\n"));
+    ($($arg:tt)*) => (emergency_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,95 @@
+use super::acpi;
+use crate::time::DateTime;
+
+use x86_64::instructions::{interrupts, port::Port};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(reg);
+        data.read()
+    }
+}
+
+fn raw_registers() -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century_register().map_or(0, read_register),
+    ]
+}
+
+/// The FADT names the CMOS register holding the century, zero if there is none.
+fn century_register() -> Option<u8> {
+    match acpi::info().fadt.as_ref()?.century {
+        0 => None,
+        reg => Some(reg),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Reads the RTC, retrying until two reads agree so an update cannot tear the result.
+pub fn read() -> DateTime {
+    let raw = interrupts::without_interrupts(|| loop {
+        let first = raw_registers();
+        if raw_registers() == first {
+            break first;
+        }
+    });
+    let status = interrupts::without_interrupts(|| read_register(REG_STATUS_B));
+
+    let decode = |value: u8| match status & STATUS_B_BINARY {
+        0 => from_bcd(value),
+        _ => value,
+    };
+
+    let pm = raw[2] & HOUR_PM != 0;
+    let mut hour = decode(raw[2] & !HOUR_PM);
+    if status & STATUS_B_24H == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = decode(raw[5]) as u16;
+    let year = match century_register() {
+        Some(_) => decode(raw[6]) as u16 * 100 + year,
+        None => 2000 + year,
+    };
+
+    DateTime {
+        year,
+        month: decode(raw[4]),
+        day: decode(raw[3]),
+        hour,
+        minute: decode(raw[1]),
+        second: decode(raw[0]),
+    }
+}
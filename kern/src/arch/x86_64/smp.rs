@@ -0,0 +1,470 @@
+use super::{
+    acpi, fpu, gdt, idt, lapic,
+    mem::{
+        paging::{self, phys_to_virt},
+        stack::KernelStack,
+        vmm,
+    },
+    task::{executor::Executor, Task},
+    tsc,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    ptr,
+    sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::Cr3,
+    structures::paging::{PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+const TRAMPOLINE: u64 = 0x8000;
+const AP_STACK_PAGES: u64 = 16;
+// Never a page address, stands in for a full flush in SHOOTDOWN_ADDR
+const FLUSH_ALL: u64 = 1;
+
+pub type Spawner = Box<dyn FnOnce() -> Task + Send>;
+
+pub struct Cpu {
+    pub apic_id: u8,
+    pub online: AtomicBool,
+    pub(crate) fault_guard: AtomicU64,
+    pub(crate) tss: AtomicU64,
+    pub(crate) fpu: fpu::CpuFpu,
+    inbox: ArrayQueue<Spawner>,
+    shootdown_seen: AtomicU64,
+}
+
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+static AP_READY: AtomicBool = AtomicBool::new(false);
+static TRAMPOLINE_RESERVED: AtomicBool = AtomicBool::new(false);
+static NEXT_SPAWN: AtomicUsize = AtomicUsize::new(0);
+static STOPPED_BY: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+static WAKEUP_VECTOR: AtomicU8 = AtomicU8::new(0);
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(0);
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_GEN: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    r#"
+.pushsection .text.ap_trampoline, "ax"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    lgdtl (ap_gdt_ptr - ap_trampoline_start + 0x8000)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_protected - ap_trampoline_start + 0x8000)
+
+.code32
+ap_protected:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movl %cr4, %eax
+    orl $((1 << 5) | (1 << 9) | (1 << 10)), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_params - ap_trampoline_start + 0x8000), %eax
+    movl %eax, %cr3
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    movl %cr0, %eax
+    andl $~(1 << 2), %eax
+    orl $((1 << 31) | (1 << 16) | (1 << 1)), %eax
+    movl %eax, %cr0
+    ljmpl $0x18, $(ap_long - ap_trampoline_start + 0x8000)
+
+.code64
+ap_long:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movq (ap_trampoline_params - ap_trampoline_start + 0x8000 + 8), %rsp
+    movq (ap_trampoline_params - ap_trampoline_start + 0x8000 + 24), %rdi
+    movq (ap_trampoline_params - ap_trampoline_start + 0x8000 + 16), %rax
+    callq *%rax
+1:
+    hlt
+    jmp 1b
+
+.align 8
+ap_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + 0x8000
+
+.align 8
+.global ap_trampoline_params
+ap_trampoline_params:
+    .quad 0, 0, 0, 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+impl Cpu {
+    fn new(apic_id: u8) -> Self {
+        Self {
+            apic_id,
+            online: AtomicBool::new(false),
+            fault_guard: AtomicU64::new(0),
+            tss: AtomicU64::new(0),
+            fpu: fpu::CpuFpu::with_simd_area(),
+            inbox: ArrayQueue::new(16),
+            shootdown_seen: AtomicU64::new(0),
+        }
+    }
+}
+
+pub fn init() {
+    let bsp = lapic::id();
+    CPUS.try_init_once(|| {
+        let mut cpus: Vec<Cpu> = match &acpi::info().madt {
+            Some(madt) => madt
+                .processors
+                .iter()
+                .filter(|cpu| cpu.enabled && cpu.apic_id <= 0xFF)
+                .map(|cpu| Cpu::new(cpu.apic_id as u8))
+                .collect(),
+            None => Vec::new(),
+        };
+        if !cpus.iter().any(|cpu| cpu.apic_id == bsp) {
+            cpus.insert(0, Cpu::new(bsp));
+        }
+        cpus
+    })
+    .expect("smp::init should only be called once");
+    cpus()[cpu_id()].online.store(true, Ordering::SeqCst);
+
+    WAKEUP_VECTOR.store(
+        idt::allocate_vector("ipi-wakeup", || {}).expect("No free vector for wakeup IPIs"),
+        Ordering::SeqCst,
+    );
+    SHOOTDOWN_VECTOR.store(
+        idt::allocate_vector("ipi-tlb-shootdown", service_shootdown)
+            .expect("No free vector for TLB shootdown IPIs"),
+        Ordering::SeqCst,
+    );
+
+    if cpus().len() > 1 {
+        if let Err(e) = install_trampoline() {
+            error!("SMP: {}", e);
+            return;
+        }
+    }
+
+    for (idx, cpu) in cpus().iter().enumerate() {
+        if cpu.apic_id == bsp {
+            continue;
+        }
+        check_ok!(
+            format_args!("[SMP] Starting CPU {} (APIC id {})", idx, cpu.apic_id),
+            start_ap(idx, cpu)
+        );
+    }
+
+    print!("SMP: {} of {} CPUs online", online_count(), cpus().len());
+    ok!();
+}
+
+/// Claims the trampoline frame, has to run before anything else allocates low memory.
+pub fn reserve_trampoline() {
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE));
+    let reserved = crate::FRAME_ALLOC.wait().lock().reserve(frame).is_ok();
+    TRAMPOLINE_RESERVED.store(reserved, Ordering::SeqCst);
+}
+
+fn install_trampoline() -> Result<(), &'static str> {
+    if !TRAMPOLINE_RESERVED.load(Ordering::SeqCst) {
+        return Err("AP trampoline frame was already in use");
+    }
+
+    // APs turn on paging while running the trampoline, so it has to stay where it is
+    paging::identity_map(
+        PhysAddr::new(TRAMPOLINE),
+        PhysAddr::new(TRAMPOLINE + 0x1000),
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        false,
+    )
+    .map_err(|_| "Failed to identity map the AP trampoline")?;
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= 0x1000);
+        ptr::copy_nonoverlapping(
+            start,
+            phys_to_virt(PhysAddr::new(TRAMPOLINE)).as_mut_ptr::<u8>(),
+            len,
+        );
+    }
+    Ok(())
+}
+
+fn trampoline_params() -> *mut TrampolineParams {
+    let offset = unsafe {
+        &ap_trampoline_params as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64
+    };
+    phys_to_virt(PhysAddr::new(TRAMPOLINE + offset)).as_mut_ptr()
+}
+
+fn start_ap(idx: usize, cpu: &Cpu) -> Result<(), &'static str> {
+    let stack = Box::leak(Box::new(KernelStack::new(AP_STACK_PAGES, "ap-stack")?));
+    let stack_top = stack.top().as_u64();
+
+    unsafe {
+        ptr::write_volatile(
+            trampoline_params(),
+            TrampolineParams {
+                cr3: Cr3::read().0.start_address().as_u64(),
+                stack: stack_top,
+                entry: ap_entry as u64,
+                cpu: idx as u64,
+            },
+        );
+    }
+    AP_READY.store(false, Ordering::SeqCst);
+
+    lapic::send_init(cpu.apic_id);
+    tsc::delay(Duration::from_millis(10));
+    for _ in 0..2 {
+        lapic::send_startup(cpu.apic_id, (TRAMPOLINE >> 12) as u8);
+        for _ in 0..1000 {
+            if AP_READY.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            tsc::delay(Duration::from_micros(200));
+        }
+    }
+
+    Err("CPU did not respond to SIPI")
+}
+
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let tss = gdt::init_ap();
+    cpus()[cpu as usize].tss.store(tss as u64, Ordering::SeqCst);
+    idt::IDT.load();
+    fpu::init_ap();
+    lapic::enable();
+    vmm::init_pat();
+
+    let gen = SHOOTDOWN_GEN.load(Ordering::SeqCst);
+    cpus()[cpu as usize]
+        .shootdown_seen
+        .store(gen, Ordering::SeqCst);
+    cpus()[cpu as usize].online.store(true, Ordering::SeqCst);
+    AP_READY.store(true, Ordering::SeqCst);
+
+    interrupts::enable();
+    Executor::new().run()
+}
+
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.try_get().map(|cpus| &cpus[..]).unwrap_or(&[])
+}
+
+pub fn cpu_id() -> usize {
+    let apic_id = lapic::id();
+    cpus()
+        .iter()
+        .position(|cpu| cpu.apic_id == apic_id)
+        .unwrap_or(0)
+}
+
+pub fn online_count() -> usize {
+    cpus()
+        .iter()
+        .filter(|cpu| cpu.online.load(Ordering::Relaxed))
+        .count()
+}
+
+/// Parks every other CPU for good before the machine is reset or powered off.
+pub fn stop_others() {
+    if online_count() <= 1 {
+        return;
+    }
+    STOPPED_BY.store(cpu_id(), Ordering::SeqCst);
+    lapic::broadcast_nmi();
+    for _ in 0..1000 {
+        if online_count() == 1 {
+            return;
+        }
+        tsc::delay(Duration::from_micros(100));
+    }
+}
+
+/// Whether an NMI on this CPU is `stop_others` asking it to park.
+pub(crate) fn stop_requested() -> bool {
+    match STOPPED_BY.load(Ordering::SeqCst) {
+        usize::MAX => false,
+        cpu => cpu != cpu_id(),
+    }
+}
+
+pub(crate) fn park() -> ! {
+    if let Some(cpu) = cpus().get(cpu_id()) {
+        cpu.online.store(false, Ordering::SeqCst);
+    }
+    // Called from the NMI handler, so neither NMIs nor interrupts get through any more
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Kicks `cpu` out of `hlt` so its executor notices new work.
+pub fn wake(cpu: usize) {
+    if cpu == cpu_id() {
+        return;
+    }
+    if let Some(target) = cpus().get(cpu) {
+        if target.online.load(Ordering::Relaxed) {
+            lapic::send_ipi(target.apic_id, WAKEUP_VECTOR.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Queues a task to be built and run by the executor on `cpu`.
+pub fn spawn_on(
+    cpu: usize,
+    spawner: impl FnOnce() -> Task + Send + 'static,
+) -> Result<(), &'static str> {
+    let target = cpus().get(cpu).ok_or("No such CPU")?;
+    if !target.online.load(Ordering::Relaxed) {
+        return Err("CPU is offline");
+    }
+    target
+        .inbox
+        .push(Box::new(spawner))
+        .map_err(|_| "CPU inbox is full")?;
+    wake(cpu);
+    Ok(())
+}
+
+/// Spreads tasks over the online CPUs in turn, the calling one included.
+pub fn spawn(spawner: impl FnOnce() -> Task + Send + 'static) -> Result<(), &'static str> {
+    let count = cpus().len().max(1);
+    let start = NEXT_SPAWN.fetch_add(1, Ordering::Relaxed);
+    let cpu = (0..count)
+        .map(|i| (start + i) % count)
+        .find(|cpu| {
+            cpus().get(*cpu).map_or(false, |target| {
+                target.online.load(Ordering::Relaxed) && !target.inbox.is_full()
+            })
+        })
+        .ok_or("Every CPU inbox is full")?;
+    spawn_on(cpu, spawner)
+}
+
+pub(crate) fn take_spawned(cpu: usize) -> Option<Spawner> {
+    cpus().get(cpu)?.inbox.pop().ok()
+}
+
+pub(crate) fn has_spawned(cpu: usize) -> bool {
+    cpus().get(cpu).map_or(false, |cpu| !cpu.inbox.is_empty())
+}
+
+/// Invalidates `addr` on every online CPU. Must be called with interrupts enabled.
+pub fn tlb_shootdown(addr: VirtAddr) {
+    debug_assert!(interrupts::are_enabled());
+    shootdown(Some(addr));
+}
+
+/// Flushes every non-global translation on every online CPU. Must be called with interrupts
+/// enabled.
+pub fn tlb_shootdown_all() {
+    debug_assert!(interrupts::are_enabled());
+    shootdown(None);
+}
+
+/// `tlb_shootdown` for the page fault handler, which runs with interrupts off on its own stack.
+/// Waiting for the lock services other shootdowns, so this is safe as long as no other lock is
+/// held.
+pub(crate) fn fault_tlb_shootdown(addr: VirtAddr) {
+    shootdown(Some(addr));
+}
+
+fn shootdown(addr: Option<VirtAddr>) {
+    flush(addr);
+
+    let others = online_count().saturating_sub(1);
+    if others == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        // Whoever holds the lock is waiting on this CPU too
+        service_shootdown();
+        spin_loop_hint();
+    };
+    SHOOTDOWN_ADDR.store(addr.map_or(FLUSH_ALL, VirtAddr::as_u64), Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+    let gen = SHOOTDOWN_GEN.fetch_add(1, Ordering::SeqCst) + 1;
+    cpus()[cpu_id()].shootdown_seen.store(gen, Ordering::SeqCst);
+    lapic::broadcast_ipi(SHOOTDOWN_VECTOR.load(Ordering::Relaxed));
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        spin_loop_hint();
+    }
+}
+
+fn flush(addr: Option<VirtAddr>) {
+    match addr {
+        Some(addr) => tlb::flush(addr),
+        None => tlb::flush_all(),
+    }
+}
+
+/// Flushes and acknowledges the current shootdown, once per CPU however often it is called.
+fn service_shootdown() {
+    let gen = SHOOTDOWN_GEN.load(Ordering::SeqCst);
+    let cpu = match cpus().get(cpu_id()) {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    if cpu.shootdown_seen.swap(gen, Ordering::SeqCst) != gen {
+        match SHOOTDOWN_ADDR.load(Ordering::SeqCst) {
+            FLUSH_ALL => flush(None),
+            addr => flush(Some(VirtAddr::new(addr))),
+        }
+        SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,24 +1,31 @@
 use super::{Task, TaskId};
+use crate::arch::{exception, smp};
 use alloc::{sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
+use core::{
+    mem,
+    task::{Context, Poll, Waker},
+};
 use crossbeam_queue::ArrayQueue;
 use hashbrown::HashMap;
 
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    cpu: usize,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, cpu: usize) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            cpu,
         }))
     }
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("task_queue full");
+        smp::wake(self.cpu);
     }
 }
 
@@ -36,6 +43,7 @@ pub struct Executor {
     tasks: HashMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: HashMap<TaskId, Waker>,
+    cpu: usize,
 }
 
 impl Executor {
@@ -44,6 +52,7 @@ impl Executor {
             tasks: HashMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
             waker_cache: HashMap::new(),
+            cpu: smp::cpu_id(),
         }
     }
 
@@ -56,10 +65,15 @@ impl Executor {
     }
 
     fn run_ready_tasks(&mut self) {
+        while let Some(spawner) = smp::take_spawned(self.cpu) {
+            self.spawn(spawner());
+        }
+
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            cpu,
         } = self;
 
         while let Ok(task_id) = task_queue.pop() {
@@ -69,14 +83,20 @@ impl Executor {
             };
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), *cpu));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
+            match exception::guard(|| task.poll(&mut context)) {
+                Some(Poll::Ready(())) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
                 }
-                Poll::Pending => {}
+                Some(Poll::Pending) => {}
+                None => {
+                    println!("Task {:?} killed", task_id);
+                    // Its state is whatever the fault left behind, dropping it could do anything
+                    mem::forget(tasks.remove(&task_id));
+                    waker_cache.remove(&task_id);
+                }
             }
         }
     }
@@ -92,7 +112,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && !smp::has_spawned(self.cpu) {
             enable_interrupts_and_hlt();
         } else {
             interrupts::enable();
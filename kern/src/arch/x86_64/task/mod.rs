@@ -1,3 +1,5 @@
+use super::fpu::{self, FpuState};
+
 use alloc::boxed::Box;
 use core::{
     future::Future,
@@ -9,6 +11,7 @@ use core::{
 pub mod executor;
 pub mod keyboard;
 pub mod mouse;
+pub mod serial;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct TaskId(u64);
@@ -23,6 +26,7 @@ impl TaskId {
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    fpu: FpuState,
 }
 
 impl Task {
@@ -30,10 +34,14 @@ impl Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
+            fpu: FpuState::new(),
         }
     }
 
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        fpu::switch_to(Some(&self.fpu));
+        let result = self.future.as_mut().poll(context);
+        fpu::switch_to(None);
+        result
     }
 }
@@ -1,4 +1,4 @@
-use crate::arch::pic::{InterruptIndex, PICS};
+use crate::arch::irq;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
@@ -6,7 +6,7 @@ use core::{
 };
 use crossbeam_queue::ArrayQueue;
 use futures_util::{stream::Stream, task::AtomicWaker};
-use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+use x86_64::instructions::port::Port;
 
 #[allow(unused)]
 pub enum MousePacket {
@@ -115,6 +115,8 @@ pub fn init() {
         write(0xF4);
         read();
     }
+
+    irq::register(12, "mouse", interrupt).ok();
 }
 
 static mut MOUSE_CYCLE: u8 = 0;
@@ -123,7 +125,7 @@ static mut MOUSE_MSG: [u8; 3] = [0u8; 3];
 pub static MOUSE_DX: spin::Mutex<i8> = spin::Mutex::new(0);
 pub static MOUSE_DY: spin::Mutex<i8> = spin::Mutex::new(0);
 
-pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+fn interrupt() {
     unsafe {
         x86_64::instructions::interrupts::without_interrupts(|| {
             match MOUSE_CYCLE {
@@ -157,8 +159,5 @@ pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut Interru
                 _ => unreachable!(),
             }
         });
-
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Mouse.into());
     }
 }
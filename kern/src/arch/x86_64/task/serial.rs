@@ -0,0 +1,71 @@
+use crate::arch::{irq, SERIAL1};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+pub fn init() {
+    // The UART itself was set up to interrupt on received data along with SERIAL1
+    lazy_static::initialize(&SERIAL1);
+    irq::register(COM1_IRQ, "serial", interrupt).ok();
+}
+
+fn interrupt() {
+    let mut lsr: Port<u8> = Port::new(COM1 + 5);
+    let mut data: Port<u8> = Port::new(COM1);
+    while unsafe { lsr.read() } & LSR_DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        if let Ok(queue) = BYTE_QUEUE.try_get() {
+            if queue.push(byte).is_ok() {
+                WAKER.wake();
+            }
+        }
+    }
+}
+
+/// Bytes received on COM1, input that arrives before a stream exists is dropped.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Ok(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::hpet;
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{spin_loop_hint, AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+const CALIBRATION_MS: u64 = 10;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+// Channel 2, lobyte/hibyte, interrupt on terminal count
+const MODE_ONESHOT: u8 = 0b1011_0000;
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const GATE_OUT: u8 = 1 << 5;
+
+const CPUID_POWER_MGMT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+
+static KHZ: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC ticks at a constant rate through frequency and sleep state changes.
+pub fn invariant() -> bool {
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_leaf >= CPUID_POWER_MGMT && unsafe { __cpuid(CPUID_POWER_MGMT) }.edx & INVARIANT_TSC != 0
+}
+
+fn measure_hpet() -> u64 {
+    let ticks = CALIBRATION_MS * 1_000_000_000_000 / hpet::period_fs();
+    let start = hpet::counter();
+    let tsc = read();
+    while hpet::counter().wrapping_sub(start) < ticks {}
+    read() - tsc
+}
+
+fn measure_pit() -> u64 {
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let mut gate: Port<u8> = Port::new(PIT_GATE);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL2);
+
+    unsafe {
+        let value = gate.read() & !(GATE_ENABLE | SPEAKER_ENABLE);
+        gate.write(value);
+        command.write(MODE_ONESHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Raising the gate starts the countdown
+        gate.write(value | GATE_ENABLE);
+        let tsc = read();
+        while gate.read() & GATE_OUT == 0 {}
+        let elapsed = read() - tsc;
+        gate.write(value);
+        elapsed
+    }
+}
+
+/// Measures the TSC frequency against the HPET, or against the PIT when there is none.
+pub fn calibrate() -> u64 {
+    let elapsed = interrupts::without_interrupts(|| match hpet::available() {
+        true => measure_hpet(),
+        false => measure_pit(),
+    });
+    let khz = elapsed / CALIBRATION_MS;
+    KHZ.store(khz, Ordering::SeqCst);
+    khz
+}
+
+pub fn khz() -> u64 {
+    KHZ.load(Ordering::Relaxed)
+}
+
+pub fn to_nanos(ticks: u64) -> u64 {
+    match khz() {
+        0 => 0,
+        khz => (ticks as u128 * 1_000_000 / khz as u128) as u64,
+    }
+}
+
+/// Busy-waits for `duration`. Works with interrupts disabled, unlike anything built on the PIT.
+pub fn delay(duration: Duration) {
+    let ticks = (duration.as_nanos() * khz() as u128 / 1_000_000) as u64;
+    let start = read();
+    while read() - start < ticks {
+        spin_loop_hint();
+    }
+}
@@ -104,6 +104,19 @@ impl Writer {
                 self.newline();
                 self.update_cursor();
             }
+            b'\r' => {
+                self.col = 0;
+                self.update_cursor();
+            }
+            b'\x08' => {
+                if self.col > 0 {
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.col = BUF_WIDTH - 1;
+                }
+                self.update_cursor();
+            }
             byte => {
                 self.buffer.chars[self.row][self.col].write(ScreenChar {
                     ascii: byte,
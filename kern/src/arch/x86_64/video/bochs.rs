@@ -1,9 +1,13 @@
-use crate::arch::pci::{PCIDevice, PCIFind, PCIBAR};
+use crate::arch::{
+    mem::vmm::{CacheMode, Mapping},
+    pci::{driver::PCIDriver, PCIDevice, PCIFind, PCIBAR},
+};
 
 use alloc::boxed::Box;
 use core::{ptr::Unique, slice};
 use lazy_static::lazy_static;
 use lib_kern::video::{GraphicsProvider, VideoMode};
+use spinning::Mutex;
 
 lazy_static! {
     pub static ref BGA_SIGNATURE: PCIFind = PCIFind::new(0x1234, 0x1111);
@@ -12,6 +16,44 @@ lazy_static! {
         height: 600,
         bpp: 32
     };
+    pub static ref ADAPTER: Mutex<Option<BochsGraphicsAdapter>> = Mutex::new(None);
+}
+
+pub struct BochsDriver;
+
+impl PCIDriver for BochsDriver {
+    fn name(&self) -> &'static str {
+        "bochs-bga"
+    }
+
+    fn patterns(&self) -> &[PCIFind] {
+        slice::from_ref(&*BGA_SIGNATURE)
+    }
+
+    fn probe(&self, dev: &PCIDevice) -> Result<(), &'static str> {
+        let mut adapter = ADAPTER.lock();
+        if adapter.is_some() {
+            return Err("Only one Bochs Graphics Adapter is supported");
+        }
+
+        let bga = BochsGraphicsAdapter::new(dev)?.init();
+        info!("0x{:08x}: version 0x{:04x}", bga.addr(), bga.version());
+        debug!("0x{:08x}: max bpp {}", bga.addr(), bga.max_bpp);
+        debug!("0x{:08x}: max width {}", bga.addr(), bga.max_width);
+        debug!("0x{:08x}: max height {}", bga.addr(), bga.max_height);
+
+        *adapter = Some(bga);
+        Ok(())
+    }
+
+    fn remove(&self, _dev: &PCIDevice) {
+        if let Some(mut bga) = ADAPTER.lock().take() {
+            bga.write_reg(
+                registers::VBE_DISPI_INDEX_ENABLE,
+                registers::VBE_DISPI_DISABLED,
+            );
+        }
+    }
 }
 
 #[allow(unused)]
@@ -40,6 +82,8 @@ pub struct BochsGraphicsAdapter {
     pub max_height: usize,
     framebuffer_bar: PCIBAR,
     mmio_bar: PCIBAR,
+    framebuffer: Mapping,
+    mmio: Mapping,
     registers: Unique<[u16; registers::VBE_DISPI_NUM_REGISTERS as usize]>,
 }
 
@@ -47,7 +91,7 @@ impl GraphicsProvider for BochsGraphicsAdapter {
     fn get_framebuffer(&self, mode: &VideoMode) -> Box<&mut [u32]> {
         let size: usize = (mode.width * mode.height) as usize;
         unsafe {
-            let slice = slice::from_raw_parts_mut(self.framebuffer_bar.addr() as *mut u32, size);
+            let slice = slice::from_raw_parts_mut(self.framebuffer.as_mut_ptr::<u32>(), size);
             box slice
         }
     }
@@ -55,25 +99,25 @@ impl GraphicsProvider for BochsGraphicsAdapter {
 
 #[allow(unused)]
 impl BochsGraphicsAdapter {
-    pub fn new(dev: &PCIDevice) -> Self {
-        let fb_bar = dev.get_bar(0);
-        let mmio_bar = dev.get_bar(2);
-        let mmio = mmio_bar.addr();
-
-        fb_bar
-            .identity_map()
-            .expect("Unable to map BGA framebuffer");
-        mmio_bar.identity_map().expect("Unable to map BGA MMIO");
-
-        Self {
+    pub fn new(dev: &PCIDevice) -> Result<Self, &'static str> {
+        let fb_bar = *dev.bar(0).ok_or("BGA has no framebuffer BAR")?;
+        let mmio_bar = *dev.bar(2).ok_or("BGA has no MMIO BAR")?;
+        let framebuffer = fb_bar.map(CacheMode::WriteCombining)?;
+        let mmio = mmio_bar.map(CacheMode::Uncached)?;
+        let registers =
+            Unique::new(unsafe { mmio.as_mut_ptr::<u8>().add(0x500) } as *mut _).unwrap();
+
+        Ok(Self {
             pci_device: *dev,
             max_bpp: 0,
             max_width: 0,
             max_height: 0,
             framebuffer_bar: fb_bar,
             mmio_bar,
-            registers: Unique::new((mmio + 0x500) as *mut _).unwrap(),
-        }
+            framebuffer,
+            mmio,
+            registers,
+        })
     }
 
     pub fn addr(&self) -> u32 {
@@ -147,8 +191,4 @@ impl BochsGraphicsAdapter {
         self.write_reg(registers::VBE_DISPI_INDEX_ENABLE, was_enabled);
         cap
     }
-
-    pub fn detect() -> Result<PCIDevice, &'static str> {
-        PCIDevice::search(&BGA_SIGNATURE, None).ok_or("Could not find Bochs Graphics Adapter")
-    }
 }
@@ -1,3 +1,16 @@
+use crate::{
+    arch::arch::{SERIAL1, WRITER},
+    schema::sys::SysSchema,
+};
+
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU8, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
 #[macro_export]
 macro_rules! ok {
     () => {
@@ -22,3 +35,311 @@ macro_rules! check_ok {
         };
     };
 }
+
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {
+        $crate::log::_log($level, $target, format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::_log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::log!(target: $target, $crate::log::Level::Error, $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Error, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::log!(target: $target, $crate::log::Level::Warn, $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Warn, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::log!(target: $target, $crate::log::Level::Info, $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Info, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::log!(target: $target, $crate::log::Level::Debug, $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Debug, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::log!(target: $target, $crate::log::Level::Trace, $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Trace, $($arg)+)
+    };
+}
+
+const RING_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn color(self) -> u8 {
+        match self {
+            Level::Error => 31,
+            Level::Warn => 33,
+            Level::Info => 32,
+            Level::Debug => 36,
+            Level::Trace => 37,
+        }
+    }
+
+    fn from_u8(level: u8) -> Option<Level> {
+        Level::ALL.iter().find(|l| **l as u8 == level).copied()
+    }
+
+    /// Parses a level name, `off` gives `None`.
+    pub fn parse(name: &str) -> Result<Option<Level>, &'static str> {
+        match name {
+            "off" => Ok(None),
+            _ => Level::ALL
+                .iter()
+                .find(|l| l.name() == name)
+                .map(|l| Some(*l))
+                .ok_or("Unknown log level"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Vga,
+    Serial,
+}
+
+impl Sink {
+    const ALL: [Sink; 2] = [Sink::Vga, Sink::Serial];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sink::Vga => "vga",
+            Sink::Serial => "serial",
+        }
+    }
+}
+
+// Highest level each sink prints, zero silences it. Indexed by `Sink`.
+static FILTERS: [AtomicU8; 2] = [
+    AtomicU8::new(Level::Info as u8),
+    AtomicU8::new(Level::Debug as u8),
+];
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Fixed-size record of everything logged, the oldest lines are dropped first.
+struct Ring {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn drop_line(&mut self) {
+        while self.len > 0 {
+            let b = self.buf[self.start];
+            self.start = (self.start + 1) % RING_SIZE;
+            self.len -= 1;
+            if b == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        (0..self.len)
+            .map(|i| self.buf[(self.start + i) % RING_SIZE])
+            .collect()
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if self.len == RING_SIZE {
+                self.drop_line();
+            }
+            self.buf[(self.start + self.len) % RING_SIZE] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Drops the color escapes from console output on its way into the ring.
+struct Plain<'a> {
+    ring: &'a mut Ring,
+    escape: bool,
+}
+
+impl Write for Plain<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\x1b' => self.escape = true,
+                _ if self.escape => self.escape = !c.is_ascii_alphabetic(),
+                _ => self.ring.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps unleveled console output, boot messages and the like, in the ring next to log lines.
+pub fn record(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let _ = Plain {
+            ring: &mut ring,
+            escape: false,
+        }
+        .write_fmt(args);
+    });
+}
+
+pub fn filter(sink: Sink) -> Option<Level> {
+    Level::from_u8(FILTERS[sink as usize].load(Ordering::Relaxed))
+}
+
+pub fn set_filter(sink: Sink, level: Option<Level>) {
+    FILTERS[sink as usize].store(level.map_or(0, |l| l as u8), Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    let tag = module.rsplit("::").next().unwrap_or(module);
+    let time = crate::time::uptime();
+    let (secs, micros) = (time.as_secs(), time.subsec_micros());
+
+    interrupts::without_interrupts(|| {
+        let _ = writeln!(
+            RING.lock(),
+            "[{:5}.{:06}] {:5} {}: {}",
+            secs,
+            micros,
+            level.name(),
+            tag,
+            args
+        );
+
+        let emit = |write: &mut dyn FnMut(fmt::Arguments)| {
+            write(format_args!(
+                "[{:5}.{:06}] \x1b[{}m{:5}\x1b[0m {}: {}\n",
+                secs,
+                micros,
+                level.color(),
+                level.name(),
+                tag,
+                args
+            ))
+        };
+        for sink in Sink::ALL.iter() {
+            if filter(*sink).map_or(true, |max| level > max) {
+                continue;
+            }
+            match sink {
+                Sink::Vga => emit(&mut |line| {
+                    let _ = WRITER.lock().write_fmt(line);
+                }),
+                Sink::Serial => emit(&mut |line| {
+                    let _ = SERIAL1.lock().write_fmt(line);
+                }),
+            }
+        }
+    });
+}
+
+pub fn register_sys(sys: &mut SysSchema) {
+    sys.insert("log", || {
+        interrupts::without_interrupts(|| RING.lock().contents())
+    });
+
+    sys.insert_writable(
+        "loglevel",
+        || {
+            Sink::ALL
+                .iter()
+                .map(|sink| {
+                    let level = filter(*sink).map_or("off", Level::name);
+                    format!("{} {}\n", sink.name(), level)
+                })
+                .collect::<String>()
+                .into_bytes()
+        },
+        |buf| {
+            let text = core::str::from_utf8(buf).or(Err("Not UTF-8"))?;
+            for line in text.lines().filter(|line| !line.trim().is_empty()) {
+                let mut words = line.split_whitespace();
+                let (sink, level) = match (words.next(), words.next(), words.next()) {
+                    (Some(sink), Some(level), None) => (sink, level),
+                    _ => return Err("Expected <sink> <level>"),
+                };
+                let sink = Sink::ALL
+                    .iter()
+                    .find(|s| s.name() == sink)
+                    .ok_or("Unknown log sink")?;
+                set_filter(*sink, Level::parse(level)?);
+            }
+            Ok(())
+        },
+    );
+}
@@ -6,7 +6,8 @@
     wake_trait,
     async_closure,
     ptr_internals,
-    box_syntax
+    box_syntax,
+    global_asm
 )]
 
 #[macro_use]
@@ -14,41 +15,61 @@ pub mod log;
 #[macro_use]
 mod arch;
 mod schema;
+mod shell;
+mod time;
 
 extern crate alloc;
 
 use arch::{
     mem,
-    mem::paging,
-    task::{executor::Executor, keyboard, mouse::MousePacketStream, Task},
+    mem::{frame, paging, space},
+    task::{executor::Executor, mouse::MousePacketStream, Task},
 };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use spinning::{Mutex, Once};
-use x86_64::{structures::paging::OffsetPageTable, VirtAddr};
+use x86_64::VirtAddr;
 
 entry_point!(kmain);
 
-pub static MAPPER: Once<Mutex<OffsetPageTable>> = Once::new();
-pub static FRAME_ALLOC: Once<Mutex<paging::BootInfoFrameAllocator>> = Once::new();
+pub static KERNEL_SPACE: Once<Mutex<space::AddressSpace>> = Once::new();
+pub static FRAME_ALLOC: Once<Mutex<frame::BitmapFrameAllocator>> = Once::new();
+pub static PHYS_MEM_OFFSET: Once<VirtAddr> = Once::new();
 
 fn kmain(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offs = VirtAddr::new(boot_info.physical_memory_offset);
-    MAPPER.call_once(|| Mutex::new(unsafe { paging::init(phys_mem_offs) }));
+    PHYS_MEM_OFFSET.call_once(|| phys_mem_offs);
+    KERNEL_SPACE.call_once(|| {
+        Mutex::new(unsafe { space::AddressSpace::kernel(paging::init(phys_mem_offs)) })
+    });
     FRAME_ALLOC.call_once(|| {
-        Mutex::new(unsafe { paging::BootInfoFrameAllocator::init(&boot_info.memory_map) })
+        Mutex::new(unsafe { frame::BitmapFrameAllocator::init(&boot_info.memory_map) })
     });
+    // Low frames go first, so claim the AP trampoline before the heap does
+    arch::smp::reserve_trampoline();
     mem::alloc::init().expect("heap initialization failed");
 
+    {
+        let frames = FRAME_ALLOC.wait().lock();
+        print!(
+            "Frame allocator loaded ({} of {} MiB free)",
+            frames.free_frames() / 256,
+            frames.usable_frames() / 256
+        );
+        ok!();
+    }
+    space::init();
+    mem::vmm::init();
+
     print!("Serial + VGA Buffer loaded");
     ok!();
     arch::init();
+    time::init();
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses()));
-    executor.spawn(Task::new(setup_devices()));
-    executor.spawn(Task::new(setup_schemas()));
-    executor.spawn(Task::new(dump()));
+    arch::smp::spawn(|| Task::new(setup_devices())).expect("failed to spawn device setup");
+    arch::smp::spawn(|| Task::new(setup_schemas())).expect("failed to spawn schema setup");
+    executor.spawn(Task::new(shell::run()));
     //executor.spawn(Task::new(arch::video::init()));
     executor.run();
 }
@@ -79,26 +100,6 @@ async fn setup_schemas() {
     );
 }
 
-use alloc::{string::String, vec::Vec};
-async fn dump() {
-    println!("\nDumping devices + schemas");
-    for dev in DEVICE_MAP.lock().dump_names() {
-        println!("Device {}", dev);
-    }
-    for dev in SCHEMA_MAP.inner().dump_names() {
-        println!("Schema {}", dev);
-    }
-    println!("\n");
-
-    println!("find: {:?}", SCHEMA_MAP.find("sys://info"));
-    let info = SCHEMA_MAP.open("sys://info");
-    println!("open: {:?}", info);
-    let info = info.unwrap();
-    let mut buf = String::new();
-    info.read_to_string(&mut buf).ok();
-    println!("read: {}", buf);
-}
-
 use lazy_static::lazy_static;
 use lib_kern::{io::DeviceMap, schema::driver::SchemaDriver};
 lazy_static! {
@@ -108,6 +109,7 @@ lazy_static! {
 
 #[panic_handler]
 fn painc(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    emergency_println!("{}", info);
+    arch::backtrace::print_current();
     arch::hlt_loop()
 }
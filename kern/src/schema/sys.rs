@@ -1,16 +1,24 @@
 use lib_kern::schema::{FileError, FileId, FileResult, FileType, Schema, SchemaId};
 
 use alloc::{
+    boxed::Box,
+    format,
     string::{String, ToString},
     vec::Vec,
 };
 use hashbrown::HashMap;
 
+pub type SysReader = Box<dyn Fn() -> Vec<u8> + Sync + Send>;
+pub type SysWriter = Box<dyn Fn(&[u8]) -> Result<(), &'static str> + Sync + Send>;
+
 pub struct SysSchema {
     schema_id: Option<SchemaId>,
-    sysinfo: HashMap<String, String>,
+    sysinfo: HashMap<String, SysReader>,
+    writers: HashMap<String, SysWriter>,
     by_path: HashMap<String, FileId>,
     by_fid: HashMap<FileId, String>,
+    // What each open file held when it was opened, so offset reads see one consistent copy
+    snapshots: HashMap<FileId, Vec<u8>>,
 }
 
 impl Schema for SysSchema {
@@ -27,7 +35,17 @@ impl Schema for SysSchema {
     }
 
     fn find(&self, path: &String) -> Option<FileType> {
-        self.sysinfo.get(path).map(|_| FileType::File)
+        if self.sysinfo.contains_key(path) {
+            return Some(FileType::File);
+        }
+
+        let dir = path.trim_end_matches('/');
+        let prefix = format!("{}/", dir);
+        if dir.is_empty() || self.sysinfo.keys().any(|key| key.starts_with(&prefix)) {
+            Some(FileType::Directory)
+        } else {
+            None
+        }
     }
 
     fn open(&mut self, path: &String, fid: FileId) -> FileResult {
@@ -38,6 +56,7 @@ impl Schema for SysSchema {
         } else {
             self.by_path.insert(path.clone(), fid);
             self.by_fid.insert(fid, path.clone());
+            self.snapshots.insert(fid, self.sysinfo[path]());
             Ok(fid)
         }
     }
@@ -48,6 +67,7 @@ impl Schema for SysSchema {
         } else {
             let spath = self.by_fid.remove(fid).unwrap();
             self.by_path.remove(&spath);
+            self.snapshots.remove(fid);
             Ok(*fid)
         }
     }
@@ -56,8 +76,8 @@ impl Schema for SysSchema {
         if !self.by_fid.contains_key(fid) {
             Err(FileError::NotFound)
         } else {
-            let val = &self.sysinfo[&self.by_fid[fid]];
-            buf.extend_from_slice(&val.as_bytes()[..]);
+            let val = self.sysinfo[&self.by_fid[fid]]();
+            buf.extend_from_slice(&val[..]);
             Ok(val.len())
         }
     }
@@ -66,22 +86,111 @@ impl Schema for SysSchema {
         if !self.by_fid.contains_key(fid) {
             Err(FileError::NotFound)
         } else {
-            buf.clone_from(&self.sysinfo[&self.by_fid[fid]]);
+            let val = self.sysinfo[&self.by_fid[fid]]();
+            buf.clone_from(&String::from_utf8_lossy(&val[..]).to_string());
             Ok(buf.len())
         }
     }
+
+    fn size(&self, fid: &FileId) -> Result<usize, FileError> {
+        let data = self.snapshots.get(fid).ok_or(FileError::NotFound)?;
+        Ok(data.len())
+    }
+
+    fn read_at(&self, fid: &FileId, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        let data = self.snapshots.get(fid).ok_or(FileError::NotFound)?;
+        let data = data.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn read_dir(&self, path: &String) -> Result<Vec<String>, FileError> {
+        let dir = path.trim_end_matches('/');
+        let prefix = match dir {
+            "" => String::new(),
+            _ => format!("{}/", dir),
+        };
+
+        let mut names: Vec<String> = self
+            .sysinfo
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .map(|key| &key[prefix.len()..])
+            .map(|rest| match rest.find('/') {
+                Some(end) => format!("{}/", &rest[..end]),
+                None => rest.to_string(),
+            })
+            .collect();
+        if names.is_empty() {
+            return Err(FileError::NotFound);
+        }
+
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn is_writable(&self, fid: &FileId) -> bool {
+        match self.by_fid.get(fid) {
+            Some(path) => self.writers.contains_key(path),
+            None => false,
+        }
+    }
+
+    // Writable sys files are commands, every write is handed over whole
+    fn write_at(&mut self, fid: &FileId, _offset: usize, buf: &[u8]) -> Result<usize, FileError> {
+        let path = self.by_fid.get(fid).ok_or(FileError::NotFound)?;
+        let writer = self.writers.get(path).ok_or(FileError::ReadOnly)?;
+        writer(buf).or(Err(FileError::InvalidInput))?;
+        let data = self.sysinfo[path]();
+        self.snapshots.insert(*fid, data);
+        Ok(buf.len())
+    }
 }
 
 impl SysSchema {
     pub fn new() -> Self {
-        let mut sysinfo = HashMap::new();
-        sysinfo.insert("info".to_string(), "Hello World".to_string());
-
-        Self {
+        let mut sys = Self {
             schema_id: None,
-            sysinfo,
+            sysinfo: HashMap::new(),
+            writers: HashMap::new(),
             by_path: HashMap::new(),
             by_fid: HashMap::new(),
-        }
+            snapshots: HashMap::new(),
+        };
+
+        sys.insert_text("info", || "Hello World".to_string());
+        crate::arch::acpi::register_sys(&mut sys);
+        crate::arch::idt::register_sys(&mut sys);
+        crate::arch::mem::register_sys(&mut sys);
+        crate::arch::pci::register_sys(&mut sys);
+        crate::arch::power::register_sys(&mut sys);
+        crate::log::register_sys(&mut sys);
+        crate::time::register_sys(&mut sys);
+
+        sys
+    }
+
+    pub fn insert(&mut self, path: &str, reader: impl Fn() -> Vec<u8> + Sync + Send + 'static) {
+        self.sysinfo.insert(path.to_string(), box reader);
+    }
+
+    pub fn insert_text(
+        &mut self,
+        path: &str,
+        reader: impl Fn() -> String + Sync + Send + 'static,
+    ) {
+        self.insert(path, move || reader().into_bytes());
+    }
+
+    pub fn insert_writable(
+        &mut self,
+        path: &str,
+        reader: impl Fn() -> Vec<u8> + Sync + Send + 'static,
+        writer: impl Fn(&[u8]) -> Result<(), &'static str> + Sync + Send + 'static,
+    ) {
+        self.insert(path, reader);
+        self.writers.insert(path.to_string(), box writer);
     }
 }
@@ -0,0 +1,249 @@
+use crate::{
+    arch::{mem, pci, power},
+    DEVICE_MAP, FRAME_ALLOC, SCHEMA_MAP,
+};
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]) -> Result<(), String>,
+}
+
+static COMMANDS: [Command; 10] = [
+    Command {
+        name: "help",
+        usage: "help",
+        run: help,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [schema://dir]",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "cat schema://file...",
+        run: cat,
+    },
+    Command {
+        name: "echo",
+        usage: "echo [text] [> schema://file]",
+        run: echo,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci",
+        run: lspci,
+    },
+    Command {
+        name: "devices",
+        usage: "devices",
+        run: devices,
+    },
+    Command {
+        name: "schemas",
+        usage: "schemas",
+        run: schemas,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        run: meminfo,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "poweroff",
+        run: poweroff,
+    },
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|command| command.name)
+}
+
+pub fn run(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let name = args[0];
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            if let Err(err) = (command.run)(&args[1..]) {
+                console_println!("{}: {}", name, err);
+            }
+        }
+        None => console_println!("{}: command not found", name),
+    }
+}
+
+/// Paths without a schema can't be split by the schema map, refuse them up front.
+fn schema_path(path: &str) -> Result<&str, String> {
+    match path.contains("://") {
+        true => Ok(path),
+        false => Err(format!("{}: expected schema://path", path)),
+    }
+}
+
+fn help(_: &[&str]) -> Result<(), String> {
+    for command in COMMANDS.iter() {
+        console_println!("{}", command.usage);
+    }
+    Ok(())
+}
+
+fn ls(args: &[&str]) -> Result<(), String> {
+    let dir = match args {
+        [] => return schemas(args),
+        [dir] => schema_path(dir)?,
+        _ => return Err("expected at most one directory".to_string()),
+    };
+
+    let names = SCHEMA_MAP
+        .read_dir(dir)
+        .map_err(|err| format!("{}: {:?}", dir, err))?;
+    for name in names {
+        console_println!("{}", name);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err("expected a file".to_string());
+    }
+
+    for path in args {
+        let file = SCHEMA_MAP
+            .open(schema_path(path)?)
+            .map_err(|err| format!("{}: {:?}", path, err))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)
+            .map_err(|err| format!("{}: {:?}", path, err))?;
+
+        let text = String::from_utf8_lossy(&buf);
+        console_print!("{}", text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            console_println!();
+        }
+    }
+    Ok(())
+}
+
+fn echo(args: &[&str]) -> Result<(), String> {
+    // Both `> file` and `>file` redirect
+    let (words, target) = match args.iter().position(|arg| arg.starts_with('>')) {
+        Some(i) if args[i].len() > 1 => (&args[..i], Some(&args[i][1..])),
+        Some(i) => (&args[..i], args.get(i + 1).copied()),
+        None => (args, None),
+    };
+    let text = format!("{}\n", words.join(" "));
+
+    let path = match target {
+        Some(path) => schema_path(path)?,
+        None if args.iter().any(|arg| arg.starts_with('>')) => {
+            return Err("expected a file after >".to_string())
+        }
+        None => {
+            console_print!("{}", text);
+            return Ok(());
+        }
+    };
+
+    let file = SCHEMA_MAP
+        .open(path)
+        .map_err(|err| format!("{}: {:?}", path, err))?;
+    if !file.is_writable().unwrap_or(false) {
+        return Err(format!("{}: read-only", path));
+    }
+    file.write_at(0, text.as_bytes())
+        .map_err(|err| format!("{}: {:?}", path, err))?;
+    Ok(())
+}
+
+fn lspci(_: &[&str]) -> Result<(), String> {
+    for dev in pci::devices() {
+        let (id, dev_type) = (dev.id(), dev.dev_type());
+        let driver = pci::driver::bound_driver(&dev.address)
+            .map(|name| format!(" [{}]", name))
+            .unwrap_or_default();
+        console_println!(
+            "{} {:04x}:{:04x} class {:02x}.{:02x}{}",
+            dev.address,
+            id.vendor_id,
+            id.device_id,
+            dev_type.class_id,
+            dev_type.subclass_id,
+            driver
+        );
+    }
+    Ok(())
+}
+
+fn devices(_: &[&str]) -> Result<(), String> {
+    let devices = DEVICE_MAP.lock();
+    let mut names = devices.dump_names();
+    names.sort();
+    for name in names {
+        console_println!("{}", name);
+    }
+    Ok(())
+}
+
+fn schemas(_: &[&str]) -> Result<(), String> {
+    let mut names: Vec<String> = SCHEMA_MAP
+        .inner()
+        .dump_names()
+        .iter()
+        .map(|name| format!("{}://", name))
+        .collect();
+    names.sort();
+    for name in names {
+        console_println!("{}", name);
+    }
+    Ok(())
+}
+
+fn reboot(_: &[&str]) -> Result<(), String> {
+    power::reboot()
+}
+
+fn poweroff(_: &[&str]) -> Result<(), String> {
+    power::shutdown()
+}
+
+fn meminfo(_: &[&str]) -> Result<(), String> {
+    let (used, usable, free) = {
+        let frames = FRAME_ALLOC.wait().lock();
+        (
+            frames.used_frames(),
+            frames.usable_frames(),
+            frames.free_frames(),
+        )
+    };
+    console_println!(
+        "frames: {} KiB used of {} KiB, {} KiB free",
+        used * 4,
+        usable * 4,
+        free * 4
+    );
+
+    let heap = mem::alloc::stats();
+    console_println!(
+        "heap: {} KiB used of {} KiB, peak {} KiB, {}% fragmented",
+        heap.used / 1024,
+        heap.size / 1024,
+        heap.peak / 1024,
+        heap.fragmentation()
+    );
+    Ok(())
+}
@@ -0,0 +1,269 @@
+mod commands;
+
+use crate::{
+    arch::task::{keyboard::ScancodeStream, serial::SerialStream},
+    SCHEMA_MAP,
+};
+
+use alloc::{format, string::String, vec::Vec};
+use futures_util::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+
+const PROMPT: &str = "> ";
+const HISTORY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Up,
+    Down,
+}
+
+fn keyboard_input() -> impl Stream<Item = Input> {
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+
+    ScancodeStream::new().filter_map(move |scancode| {
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => keyboard.process_keyevent(event),
+            _ => None,
+        };
+        future::ready(key.and_then(|key| match key {
+            DecodedKey::Unicode('\n') => Some(Input::Enter),
+            DecodedKey::Unicode('\x08') => Some(Input::Backspace),
+            DecodedKey::Unicode('\t') => Some(Input::Tab),
+            DecodedKey::Unicode(c) if !c.is_control() => Some(Input::Char(c)),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Input::Up),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Input::Down),
+            _ => None,
+        }))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+fn serial_input() -> impl Stream<Item = Input> {
+    let mut escape = Escape::None;
+    let mut prev = 0u8;
+
+    SerialStream::new().filter_map(move |byte| {
+        let last = core::mem::replace(&mut prev, byte);
+        let input = match escape {
+            Escape::Esc => {
+                escape = if byte == b'[' {
+                    Escape::Csi
+                } else {
+                    Escape::None
+                };
+                None
+            }
+            Escape::Csi => {
+                // Parameter bytes keep the sequence going, anything else ends it
+                if !byte.is_ascii_digit() && byte != b';' {
+                    escape = Escape::None;
+                }
+                match byte {
+                    b'A' => Some(Input::Up),
+                    b'B' => Some(Input::Down),
+                    _ => None,
+                }
+            }
+            Escape::None => match byte {
+                0x1B => {
+                    escape = Escape::Esc;
+                    None
+                }
+                b'\r' => Some(Input::Enter),
+                // Terminals send \r, \n or both for Enter
+                b'\n' if last != b'\r' => Some(Input::Enter),
+                0x08 | 0x7F => Some(Input::Backspace),
+                b'\t' => Some(Input::Tab),
+                b' '..=b'~' => Some(Input::Char(byte as char)),
+                _ => None,
+            },
+        };
+        future::ready(input)
+    })
+}
+
+/// Every completion of `word`: command names in first position, otherwise schema paths.
+fn completions(word: &str, first: bool) -> Vec<String> {
+    if first && !word.contains(':') {
+        return commands::names()
+            .filter(|name| name.starts_with(word))
+            .map(|name| format!("{} ", name))
+            .collect();
+    }
+
+    if !word.contains("://") {
+        return SCHEMA_MAP
+            .inner()
+            .dump_names()
+            .iter()
+            .map(|name| format!("{}://", name))
+            .filter(|path| path.starts_with(word))
+            .collect();
+    }
+
+    let split = word.rfind('/').unwrap() + 1;
+    let (dir, prefix) = word.split_at(split);
+    SCHEMA_MAP
+        .read_dir(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| match name.ends_with('/') {
+            true => format!("{}{}", dir, name),
+            false => format!("{}{} ", dir, name),
+        })
+        .collect()
+}
+
+fn common_prefix(words: &[String]) -> &str {
+    let first = &words[0];
+    let len = words[1..].iter().fold(first.len(), |len, word| {
+        first[..len]
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(word.len()), |((i, _), _)| i)
+    });
+    &first[..len]
+}
+
+struct Shell {
+    line: String,
+    history: Vec<String>,
+    // Entry being shown while browsing history, `history.len()` is the line being typed
+    browsing: usize,
+    draft: String,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Self {
+            line: String::new(),
+            history: Vec::new(),
+            browsing: 0,
+            draft: String::new(),
+        }
+    }
+
+    fn prompt(&self) {
+        console_print!("{}{}", PROMPT, self.line);
+    }
+
+    fn replace_line(&mut self, line: String) {
+        for _ in self.line.chars() {
+            console_print!("\x08 \x08");
+        }
+        self.line = line;
+        console_print!("{}", self.line);
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.line.push_str(s);
+        console_print!("{}", s);
+    }
+
+    fn submit(&mut self) {
+        console_println!();
+        let line = core::mem::replace(&mut self.line, String::new());
+        let line = line.trim();
+
+        if !line.is_empty() {
+            if self.history.last().map(String::as_str) != Some(line) {
+                if self.history.len() == HISTORY_LEN {
+                    self.history.remove(0);
+                }
+                self.history.push(String::from(line));
+            }
+            commands::run(line);
+        }
+
+        self.browsing = self.history.len();
+        self.draft.clear();
+        self.prompt();
+    }
+
+    fn complete(&mut self) {
+        let start = self.line.rfind(' ').map_or(0, |i| i + 1);
+        let word = String::from(&self.line[start..]);
+        let first = self.line[..start].trim().is_empty();
+
+        let candidates = completions(&word, first);
+        if candidates.is_empty() {
+            return;
+        }
+
+        let prefix = common_prefix(&candidates);
+        if prefix.len() > word.len() {
+            let rest = String::from(&prefix[word.len()..]);
+            self.push_str(&rest);
+            return;
+        }
+
+        // Nothing more in common, show the choices and redraw the line
+        console_println!();
+        let dir = word.rfind('/').map_or(0, |i| i + 1);
+        for candidate in candidates.iter() {
+            console_print!("{}  ", candidate[dir..].trim_end());
+        }
+        console_println!();
+        self.prompt();
+    }
+
+    fn handle(&mut self, input: Input) {
+        match input {
+            Input::Char(c) => {
+                self.line.push(c);
+                console_print!("{}", c);
+            }
+            Input::Backspace => {
+                if self.line.pop().is_some() {
+                    console_print!("\x08 \x08");
+                }
+            }
+            Input::Enter => self.submit(),
+            Input::Tab => self.complete(),
+            Input::Up if self.browsing > 0 => {
+                if self.browsing == self.history.len() {
+                    self.draft = self.line.clone();
+                }
+                self.browsing -= 1;
+                self.replace_line(self.history[self.browsing].clone());
+            }
+            Input::Down if self.browsing < self.history.len() => {
+                self.browsing += 1;
+                let line = match self.history.get(self.browsing) {
+                    Some(line) => line.clone(),
+                    None => self.draft.clone(),
+                };
+                self.replace_line(line);
+            }
+            Input::Up | Input::Down => {}
+        }
+    }
+}
+
+/// Reads lines from the keyboard and COM1 and runs them, for as long as the kernel is up.
+pub async fn run() {
+    let mut input = stream::select(keyboard_input(), serial_input());
+    let mut shell = Shell::new();
+
+    console_println!("\nType help for a list of commands");
+    shell.prompt();
+    while let Some(key) = input.next().await {
+        shell.handle(key);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    arch::{clock, rtc},
+    schema::sys::SysSchema,
+};
+
+use alloc::{format, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    ops::{Add, Sub},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar
+const UNIX_EPOCH_DAYS: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+/// A calendar date and time of day, in UTC as far as the kernel is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch, dates before it clamp to zero.
+    pub fn to_unix(&self) -> u64 {
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+
+        let secs = self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        (days.max(0) as u64) * SECS_PER_DAY + secs
+    }
+
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64 + UNIX_EPOCH_DAYS;
+        let secs = secs % SECS_PER_DAY;
+
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// A point on the monotonic clock with nanosecond resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(clock::nanos())
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+static BOOT_UNIX: AtomicU64 = AtomicU64::new(0);
+static BOOT_INSTANT: AtomicU64 = AtomicU64::new(0);
+
+// Pending sleeps by id, each sleep has at most one entry
+static TIMERS: Mutex<Vec<(u64, Instant, Waker)>> = Mutex::new(Vec::new());
+static NEXT_TIMER: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the wall clock from the RTC, from here on it is advanced by the monotonic clock.
+pub fn init() {
+    let date = rtc::read();
+    BOOT_INSTANT.store(Instant::now().0, Ordering::SeqCst);
+    BOOT_UNIX.store(date.to_unix(), Ordering::SeqCst);
+
+    print!("Wall clock set to {} UTC", date);
+    ok!();
+}
+
+/// Time since the wall clock was set, unaffected by changes to it.
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(BOOT_INSTANT.load(Ordering::SeqCst)))
+}
+
+/// Time since the Unix epoch, for timestamps.
+pub fn now() -> Duration {
+    Duration::from_secs(BOOT_UNIX.load(Ordering::SeqCst)) + uptime()
+}
+
+pub fn date_time() -> DateTime {
+    DateTime::from_unix(now().as_secs())
+}
+
+/// Completes once `deadline` has passed.
+pub struct Sleep {
+    deadline: Instant,
+    // Set once the sleep has an entry in `TIMERS`
+    timer: Option<u64>,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline;
+        let id = *self
+            .timer
+            .get_or_insert_with(|| NEXT_TIMER.fetch_add(1, Ordering::Relaxed));
+        interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.iter_mut().find(|(timer, _, _)| *timer == id) {
+                Some((_, _, waker)) if waker.will_wake(cx.waker()) => {}
+                Some((_, _, waker)) => *waker = cx.waker().clone(),
+                None => timers.push((id, deadline, cx.waker().clone())),
+            }
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer {
+            interrupts::without_interrupts(|| {
+                TIMERS.lock().retain(|(timer, _, _)| *timer != id);
+            });
+        }
+    }
+}
+
+/// Wakes the sleepers whose deadline has passed, called from the timer interrupt.
+pub fn expire_timers() {
+    let now = Instant::now();
+    TIMERS.lock().retain(|(_, deadline, waker)| {
+        if *deadline > now {
+            return true;
+        }
+        waker.wake_by_ref();
+        false
+    });
+}
+
+pub fn register_sys(sys: &mut SysSchema) {
+    sys.insert_text("time", || {
+        let now = now();
+        let uptime = uptime();
+        format!(
+            "date: {} UTC\nunix: {}.{:03}\nuptime: {}.{:03}\n",
+            DateTime::from_unix(now.as_secs()),
+            now.as_secs(),
+            now.subsec_millis(),
+            uptime.as_secs(),
+            uptime.subsec_millis()
+        )
+    });
+
+    sys.insert_text("boot", || {
+        clock::boot_steps()
+            .iter()
+            .map(|(name, nanos)| {
+                format!(
+                    "{}: {}.{:03} ms\n",
+                    name,
+                    nanos / 1_000_000,
+                    nanos / 1000 % 1000
+                )
+            })
+            .collect()
+    });
+}
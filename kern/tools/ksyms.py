@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+# Embeds the kernel symbol table into the reserved .ksyms section of a linked
+# kernel ELF, then hands the ELF on to the real runner. Used as the cargo
+# runner, which appends the ELF path and any arguments after `--`:
+#
+#   tools/ksyms.py [runner...] -- <kernel-elf> [args...]
+
+import os
+import re
+import struct
+import subprocess
+import sys
+
+MAGIC = b"KSYM"
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def run_nm(elf):
+    for nm in ("llvm-nm", "rust-nm", "nm"):
+        try:
+            out = subprocess.run(
+                [nm, "--defined-only", "--demangle", "-n", elf],
+                check=True,
+                capture_output=True,
+                text=True,
+            ).stdout
+            return out
+        except (OSError, subprocess.CalledProcessError):
+            continue
+    sys.exit("ksyms: no working nm found")
+
+
+def symbols(elf):
+    syms = []
+    for line in run_nm(elf).splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tTwW":
+            continue
+        name = HASH_SUFFIX.sub("", parts[2])
+        syms.append((int(parts[0], 16), name.encode()))
+    return syms
+
+
+def build_blob(syms):
+    strings = bytearray()
+    entries = bytearray()
+    for addr, name in syms:
+        entries += struct.pack("<QII", addr, len(strings), len(name))
+        strings += name
+    return MAGIC + struct.pack("<I", len(syms)) + entries + strings
+
+
+def find_section(data, name):
+    shoff, = struct.unpack_from("<Q", data, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", data, 0x3A)
+
+    def section(i):
+        return struct.unpack_from("<IIQQQQIIQQ", data, shoff + i * shentsize)
+
+    strtab_off = section(shstrndx)[4]
+    for i in range(shnum):
+        sh = section(i)
+        end = data.index(b"\0", strtab_off + sh[0])
+        if data[strtab_off + sh[0]:end] == name:
+            return sh[4], sh[5]
+    sys.exit("ksyms: no .ksyms section found")
+
+
+def main():
+    if "--" not in sys.argv or sys.argv.index("--") + 1 >= len(sys.argv):
+        sys.exit("usage: ksyms.py [runner...] -- <kernel-elf> [args...]")
+    split = sys.argv.index("--")
+    runner, elf, args = sys.argv[1:split], sys.argv[split + 1], sys.argv[split + 2:]
+
+    with open(elf, "rb") as f:
+        data = bytearray(f.read())
+    offset, size = find_section(data, b".ksyms")
+
+    blob = build_blob(symbols(elf))
+    if len(blob) > size:
+        sys.exit("ksyms: symbol table is %d bytes, .ksyms holds %d" % (len(blob), size))
+
+    data[offset:offset + size] = blob + bytes(size - len(blob))
+    with open(elf, "wb") as f:
+        f.write(data)
+
+    if runner:
+        os.execvp(runner[0], runner + [elf] + args)
+
+
+if __name__ == "__main__":
+    main()
@@ -4,6 +4,7 @@
 pub mod ansi;
 pub mod gfx;
 pub mod io;
+pub mod net;
 pub mod schema;
 pub mod video;
 
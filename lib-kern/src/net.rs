@@ -0,0 +1,87 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::fmt;
+use spinning::{Mutex, MutexGuard};
+
+pub const ETH_FRAME_MAX: usize = 1518;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LinkStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug)]
+pub enum NetError {
+    LinkDown,
+    FrameTooLarge,
+    QueueFull,
+}
+
+pub trait NetworkDevice {
+    fn mac_address(&self) -> MacAddress;
+    fn link_status(&self) -> LinkStatus;
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), NetError>;
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+pub struct InterfaceMap {
+    next_iface: u16,
+    iface_names: BTreeMap<&'static str, u16>,
+    iface_handles: BTreeMap<u16, Mutex<Box<dyn NetworkDevice + Sync + Send>>>,
+}
+
+impl InterfaceMap {
+    pub fn new() -> Self {
+        Self {
+            next_iface: 0,
+            iface_names: BTreeMap::new(),
+            iface_handles: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        name: &'static str,
+        device: impl NetworkDevice + Sync + Send + 'static,
+    ) -> Result<(), ()> {
+        if self.iface_names.contains_key(name) {
+            return Err(());
+        }
+
+        self.iface_names.insert(name, self.next_iface);
+        self.iface_handles
+            .insert(self.next_iface, Mutex::new(box device));
+        self.next_iface += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), ()> {
+        let handle = self.iface_names.remove(name).ok_or(())?;
+        self.iface_handles.remove(&handle).ok_or(())?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<MutexGuard<Box<dyn NetworkDevice + Sync + Send>>> {
+        let handle = self.iface_names.get(name)?;
+        Some(self.iface_handles.get(handle)?.lock())
+    }
+
+    pub fn dump_names(&self) -> Vec<&&'static str> {
+        self.iface_names.keys().collect()
+    }
+}
@@ -24,6 +24,10 @@ impl SchemaDriver {
         self._inner.lock().find(path)
     }
 
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, SchemaError> {
+        self._inner.lock().read_dir(path)
+    }
+
     pub fn open(&self, path: &str) -> Result<File, SchemaError> {
         Ok(File {
             fid: self._inner.lock().open(path)?,
@@ -43,6 +47,27 @@ impl SchemaDriver {
         self._inner.lock().read_to_string(fid, buf)
     }
 
+    pub fn size(&self, fid: &FileId) -> Result<usize, SchemaError> {
+        self._inner.lock().size(fid)
+    }
+
+    pub fn read_at(
+        &self,
+        fid: &FileId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, SchemaError> {
+        self._inner.lock().read_at(fid, offset, buf)
+    }
+
+    pub fn is_writable(&self, fid: &FileId) -> Result<bool, SchemaError> {
+        self._inner.lock().is_writable(fid)
+    }
+
+    pub fn write_at(&self, fid: &FileId, offset: usize, buf: &[u8]) -> Result<usize, SchemaError> {
+        self._inner.lock().write_at(fid, offset, buf)
+    }
+
     pub fn inner(&self) -> MutexGuard<SchemaMap> {
         self._inner.lock()
     }
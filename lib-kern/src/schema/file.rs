@@ -31,6 +31,31 @@ impl File {
             .lock()
             .read_to_string(&self.fid, buf)
     }
+
+    pub fn size(&self) -> Result<usize, SchemaError> {
+        Weak::upgrade(&self.schema).unwrap().lock().size(&self.fid)
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SchemaError> {
+        Weak::upgrade(&self.schema)
+            .unwrap()
+            .lock()
+            .read_at(&self.fid, offset, buf)
+    }
+
+    pub fn is_writable(&self) -> Result<bool, SchemaError> {
+        Weak::upgrade(&self.schema)
+            .unwrap()
+            .lock()
+            .is_writable(&self.fid)
+    }
+
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, SchemaError> {
+        Weak::upgrade(&self.schema)
+            .unwrap()
+            .lock()
+            .write_at(&self.fid, offset, buf)
+    }
 }
 
 impl Drop for File {
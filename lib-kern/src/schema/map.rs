@@ -65,6 +65,22 @@ impl SchemaMap {
         schema.lock().find(&rest).ok_or(SchemaError::NotFound(rest))
     }
 
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, SchemaError> {
+        let (schema, rest) = split_schema(path);
+
+        if !self.schema_names.contains_key(&schema) {
+            return Err(SchemaError::NoSchema(schema));
+        }
+
+        let handle = self.schema_names[&schema];
+        let schema = &self.schema_handles[&handle];
+
+        schema
+            .lock()
+            .read_dir(&rest)
+            .or(Err(SchemaError::NotFound(rest)))
+    }
+
     pub fn open(&mut self, path: &str) -> Result<FileId, SchemaError> {
         let spath = path.to_string();
         if self.path_fid.contains_key(&spath) {
@@ -85,6 +101,7 @@ impl SchemaMap {
                 match schema.lock().open(&rest, FileId(self.next_fid)) {
                     Err(FileError::NotFound) => Err(SchemaError::NotFound(rest)),
                     Err(FileError::AlreadyOpen) => Err(SchemaError::AlreadyOpen(spath)),
+                    Err(FileError::ReadOnly) | Err(FileError::InvalidInput) => unreachable!(),
                     Ok(fid) => {
                         self.next_fid += 1;
                         self.path_fid.insert(spath.clone(), fid);
@@ -116,7 +133,9 @@ impl SchemaMap {
                 Ok(fid)
             }
             Err(FileError::NotFound) => Err(SchemaError::NotOpen(*fid)),
-            Err(FileError::AlreadyOpen) => unreachable!(),
+            Err(FileError::AlreadyOpen)
+            | Err(FileError::ReadOnly)
+            | Err(FileError::InvalidInput) => unreachable!(),
         }
     }
 
@@ -148,6 +167,61 @@ impl SchemaMap {
             .or(Err(SchemaError::NoRead(*fid)))
     }
 
+    pub fn size(&self, fid: &FileId) -> Result<usize, SchemaError> {
+        if !self.fid_path.contains_key(fid) {
+            return Err(SchemaError::NotOpen(*fid));
+        }
+
+        let handle = self.fid_schema[fid];
+        let schema = &self.schema_handles[&handle];
+
+        schema.lock().size(fid).or(Err(SchemaError::NoRead(*fid)))
+    }
+
+    pub fn read_at(
+        &self,
+        fid: &FileId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, SchemaError> {
+        if !self.fid_path.contains_key(fid) {
+            return Err(SchemaError::NotOpen(*fid));
+        }
+
+        let handle = self.fid_schema[fid];
+        let schema = &self.schema_handles[&handle];
+
+        schema
+            .lock()
+            .read_at(fid, offset, buf)
+            .or(Err(SchemaError::NoRead(*fid)))
+    }
+
+    pub fn is_writable(&self, fid: &FileId) -> Result<bool, SchemaError> {
+        if !self.fid_path.contains_key(fid) {
+            return Err(SchemaError::NotOpen(*fid));
+        }
+
+        let handle = self.fid_schema[fid];
+        let schema = &self.schema_handles[&handle];
+
+        Ok(schema.lock().is_writable(fid))
+    }
+
+    pub fn write_at(&self, fid: &FileId, offset: usize, buf: &[u8]) -> Result<usize, SchemaError> {
+        if !self.fid_path.contains_key(fid) {
+            return Err(SchemaError::NotOpen(*fid));
+        }
+
+        let handle = self.fid_schema[fid];
+        let schema = &self.schema_handles[&handle];
+
+        schema
+            .lock()
+            .write_at(fid, offset, buf)
+            .or(Err(SchemaError::NoWrite(*fid)))
+    }
+
     pub fn dump_names(&self) -> Vec<&String> {
         self.schema_names.keys().collect()
     }
@@ -24,6 +24,8 @@ pub enum FileType {
 pub enum FileError {
     NotFound,
     AlreadyOpen,
+    ReadOnly,
+    InvalidInput,
 }
 
 pub trait Schema {
@@ -37,10 +39,27 @@ pub trait Schema {
 
     fn read_to_end(&self, fid: &FileId, buf: &mut Vec<u8>) -> Result<usize, FileError>;
     fn read_to_string(&self, fid: &FileId, buf: &mut String) -> Result<usize, FileError>;
+
+    fn size(&self, fid: &FileId) -> Result<usize, FileError>;
+    /// Reads from `offset` into `buf`, a short count means the end of the file was reached.
+    fn read_at(&self, fid: &FileId, offset: usize, buf: &mut [u8]) -> Result<usize, FileError>;
+
+    fn is_writable(&self, _fid: &FileId) -> bool {
+        false
+    }
+
+    fn write_at(&mut self, _fid: &FileId, _offset: usize, _buf: &[u8]) -> Result<usize, FileError> {
+        Err(FileError::ReadOnly)
+    }
+
+    /// Entry names in the directory at `path`, subdirectories end with a slash.
+    fn read_dir(&self, _path: &String) -> Result<Vec<String>, FileError> {
+        Err(FileError::NotFound)
+    }
 }
 
 pub(self) fn split_schema(path: &str) -> (String, String) {
-    if let &[schema, rest] = path.split(":").collect::<Vec<_>>().as_slice() {
+    if let &[schema, rest] = path.splitn(2, ":").collect::<Vec<_>>().as_slice() {
         (
             schema.to_string(),
             rest.trim_start_matches("//").to_string(),
@@ -58,4 +77,5 @@ pub enum SchemaError {
     AlreadyOpen(String),
     NotOpen(FileId),
     NoRead(FileId),
+    NoWrite(FileId),
 }